@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+use emk_rs::analysis::FieldCensus;
+use emk_rs::util::EMK_MAGIC;
+
+/// Tabulates the unknown tag table fields of every EMK file in a directory
+pub fn main() {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .expect("usage: analyze <directory>");
+
+    let mut census = FieldCensus::new();
+    census
+        .add_dir(&dir, EMK_MAGIC.to_be_bytes().as_ref())
+        .unwrap();
+
+    for (path, e) in &census.failed {
+        eprintln!("{}: {e}", path.display());
+    }
+    print!("{census}");
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Must never panic, no matter how broken the input is
+    let _ = emk_rs::types::EmkFile::from_bytes_decrypted(data);
+});
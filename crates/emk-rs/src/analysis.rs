@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::error::{EmkError, Result};
+use crate::stream::EmkStreamReader;
+use crate::types::TagEntry;
+
+/// Tabulates the values of the unknown tag table fields across many files.
+///
+/// Only the tag tables are read, so even large libraries can be scanned quickly.
+#[derive(Debug, Default)]
+pub struct FieldCensus {
+    /// Number of files that were read
+    pub files: usize,
+    /// Files that could not be read
+    pub failed: Vec<(PathBuf, EmkError)>,
+    /// Number of times every value was seen, by tag and field
+    pub values: BTreeMap<(String, &'static str), BTreeMap<String, usize>>,
+}
+
+impl FieldCensus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the unknown fields of the entries of one file
+    pub fn add_entries(&mut self, entries: &[TagEntry]) {
+        self.files += 1;
+        for entry in entries {
+            for (field, value) in entry.unknown_fields() {
+                *self
+                    .values
+                    .entry((entry.tag.clone(), field))
+                    .or_default()
+                    .entry(format!("{value:?}"))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    /// Reads a single file with the given key
+    pub fn add_file(&mut self, path: &Path, key: &[u8]) -> Result<()> {
+        let file = BufReader::new(File::open(path)?);
+        let reader = EmkStreamReader::new(file, key)?;
+        self.add_entries(reader.entries());
+        Ok(())
+    }
+
+    /// Reads every `.emk` file in a directory and its subdirectories.
+    ///
+    /// Files that cannot be read are recorded in [`FieldCensus::failed`], only errors listing the
+    /// directories are returned.
+    pub fn add_dir(&mut self, dir: &Path, key: &[u8]) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.add_dir(&path, key)?;
+            } else if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("emk"))
+            {
+                if let Err(e) = self.add_file(&path, key) {
+                    self.failed.push((path, e));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FieldCensus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} files read, {} failed", self.files, self.failed.len())?;
+        writeln!(
+            f,
+            "{:<16} {:<6} {:<24} {:>8}",
+            "TAG", "FIELD", "VALUE", "COUNT"
+        )?;
+        for ((tag, field), values) in &self.values {
+            for (value, count) in values {
+                writeln!(f, "{tag:<16} {field:<6} {value:<24} {count:>8}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FieldCensus;
+    use crate::test_util::TEST_DATA;
+    use crate::types::EmkReader;
+
+    #[test]
+    fn test_census() {
+        let reader = EmkReader::decrypt_default_key(TEST_DATA).unwrap();
+        let mut census = FieldCensus::new();
+        census.add_entries(reader.entries());
+        census.add_entries(reader.entries());
+
+        assert_eq!(census.files, 2);
+        let unk5 = &census.values[&("MIDI_DATA".to_string(), "unk5")];
+        assert_eq!(unk5.get("Byte(1)"), Some(&2));
+        let unk7 = &census.values[&("HEADER".to_string(), "unk7")];
+        assert_eq!(unk7.get("String()"), Some(&2));
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt::Write;
+
+use crate::timed::TimedLine;
+use crate::types::SongInfo;
+
+/// How a syllable is highlighted when its time comes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KaraokeEffect {
+    /// `\k`, the syllable switches colour at once
+    Switch,
+    /// `\kf`, the colour sweeps across the syllable, like the Extreme Karaoke cursor
+    #[default]
+    Fill,
+}
+
+impl KaraokeEffect {
+    fn tag(self) -> &'static str {
+        match self {
+            KaraokeEffect::Switch => "k",
+            KaraokeEffect::Fill => "kf",
+        }
+    }
+}
+
+/// Look of the lyrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssStyle {
+    pub font: String,
+    pub font_size: u32,
+    /// Colour of sung syllables, as `0xRRGGBB`
+    pub primary_colour: u32,
+    /// Colour of syllables not sung yet, as `0xRRGGBB`
+    pub secondary_colour: u32,
+    pub outline_colour: u32,
+    pub outline: u32,
+    pub bold: bool,
+    /// Distance between the lyrics and the edge of the screen, in pixels
+    pub margin: u32,
+}
+
+impl Default for AssStyle {
+    fn default() -> Self {
+        Self {
+            font: "Tahoma".to_string(),
+            font_size: 48,
+            primary_colour: 0x0000FF,
+            secondary_colour: 0xFFFFFF,
+            outline_colour: 0x000000,
+            outline: 3,
+            bold: true,
+            margin: 40,
+        }
+    }
+}
+
+/// Options of [`to_ass`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssOptions {
+    style: AssStyle,
+    effect: KaraokeEffect,
+    two_lines: bool,
+    preroll: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        Self {
+            style: AssStyle::default(),
+            effect: KaraokeEffect::default(),
+            two_lines: true,
+            preroll: 2000,
+            width: 1280,
+            height: 720,
+        }
+    }
+}
+
+impl AssOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn style(mut self, style: AssStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    pub fn effect(mut self, effect: KaraokeEffect) -> Self {
+        self.effect = effect;
+        self
+    }
+
+    /// Alternates lines between a left-aligned upper row and a right-aligned lower row, like the
+    /// Extreme Karaoke screen, instead of showing one centred line at a time
+    pub fn two_lines(mut self, two_lines: bool) -> Self {
+        self.two_lines = two_lines;
+        self
+    }
+
+    /// Shows every line up to `millis` before its first syllable is sung
+    pub fn preroll(mut self, millis: u32) -> Self {
+        self.preroll = millis;
+        self
+    }
+
+    /// Size of the video the subtitles are made for
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+}
+
+/// Formats milliseconds as an ASS timestamp, `h:mm:ss.cc`
+pub fn format_timestamp(ms: u32) -> String {
+    let cs = centis(ms);
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+fn centis(ms: u32) -> u32 {
+    (ms + 5) / 10
+}
+
+/// Converts `0xRRGGBB` to the `&HAABBGGRR` notation of ASS
+fn colour(rgb: u32) -> String {
+    let (r, g, b) = (rgb >> 16 & 0xFF, rgb >> 8 & 0xFF, rgb & 0xFF);
+    format!("&H00{:02X}{:02X}{:02X}", b, g, r)
+}
+
+/// Keeps braces in the lyrics from being read as override blocks. ASS has no escape for them that
+/// every renderer understands, so they become full-width braces.
+fn escape(text: &str) -> String {
+    text.replace('{', "\u{FF5B}").replace('}', "\u{FF5D}")
+}
+
+/// Writes an Advanced SubStation Alpha script from timed lines in milliseconds, with a `Dialogue`
+/// event for every sung line and a karaoke tag for every syllable
+pub fn to_ass(lines: &[TimedLine], song: Option<&SongInfo>, options: &AssOptions) -> String {
+    let style = &options.style;
+    let mut out = String::new();
+
+    let _ = writeln!(out, "[Script Info]");
+    if let Some(song) = song {
+        let _ = writeln!(out, "Title: {} - {}", song.title.trim(), song.artist.trim());
+    }
+    let _ = writeln!(out, "ScriptType: v4.00+");
+    let _ = writeln!(out, "WrapStyle: 2");
+    let _ = writeln!(out, "PlayResX: {}", options.width);
+    let _ = writeln!(out, "PlayResY: {}", options.height);
+    let _ = writeln!(out);
+
+    // Alignments follow the numpad: 1 is bottom left, 2 bottom centre, 3 bottom right
+    let line_height = style.font_size * 3 / 2;
+    let styles: &[(&str, u32, u32)] = if options.two_lines {
+        &[
+            ("Top", 1, style.margin + line_height),
+            ("Bottom", 3, style.margin),
+        ]
+    } else {
+        &[("Lyrics", 2, style.margin)]
+    };
+    let _ = writeln!(out, "[V4+ Styles]");
+    let _ = writeln!(
+        out,
+        "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, \
+         BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, \
+         BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding"
+    );
+    for (name, alignment, margin_v) in styles {
+        let _ = writeln!(
+            out,
+            "Style: {},{},{},{},{},{},&H80000000,{},0,0,0,100,100,0,0,1,{},0,{},{},{},{},1",
+            name,
+            style.font,
+            style.font_size,
+            colour(style.primary_colour),
+            colour(style.secondary_colour),
+            colour(style.outline_colour),
+            if style.bold { -1 } else { 0 },
+            style.outline,
+            alignment,
+            style.margin,
+            style.margin,
+            margin_v,
+        );
+    }
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "[Events]");
+    let _ = writeln!(
+        out,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    );
+    let lines = lines
+        .iter()
+        .filter(|line| !line.text().trim().is_empty())
+        .collect::<Vec<_>>();
+    for (i, line) in lines.iter().enumerate() {
+        let (Some(start), Some(end)) = (line.start(), line.end()) else {
+            continue;
+        };
+        // A line replaces the one shown before it in the same row, so it only appears once that
+        // one is sung
+        let previous = i.checked_sub(styles.len()).map(|j| &lines[j]);
+        let shown = start
+            .saturating_sub(options.preroll)
+            .max(previous.and_then(|l| l.end()).unwrap_or(0))
+            .min(start);
+
+        let mut text = String::new();
+        let lead_in = centis(start) - centis(shown);
+        if lead_in > 0 {
+            let _ = write!(text, "{{\\k{}}}", lead_in);
+        }
+        for syllable in &line.syllables {
+            let duration = centis(syllable.end).saturating_sub(centis(syllable.start));
+            let _ = write!(
+                text,
+                "{{\\{}{}}}{}",
+                options.effect.tag(),
+                duration,
+                escape(&syllable.text)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "Dialogue: 0,{},{},{},,0,0,0,,{}",
+            format_timestamp(shown),
+            format_timestamp(end),
+            styles[i % styles.len()].0,
+            text.trim_end()
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_timestamp, to_ass, AssOptions, KaraokeEffect};
+    use crate::test_util::sample;
+    use crate::timed::{TimedLine, TimedSyllable};
+
+    fn line(syllables: &[(&str, u32, u32)]) -> TimedLine {
+        TimedLine {
+            syllables: syllables
+                .iter()
+                .map(|&(text, start, end)| TimedSyllable {
+                    text: text.to_string(),
+                    start,
+                    end,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "0:00:00.00");
+        assert_eq!(format_timestamp(3_723_456), "1:02:03.46");
+    }
+
+    #[test]
+    fn test_dialogue() {
+        let lines = [
+            line(&[("Jen", 3000, 3500), ("ny {", 3500, 4000)]),
+            line(&[("8675", 4000, 5000)]),
+            line(&[("309", 5000, 6000)]),
+        ];
+        let options = AssOptions::new().preroll(1000);
+        let ass = to_ass(&lines, None, &options);
+        let events = ass
+            .lines()
+            .filter(|l| l.starts_with("Dialogue:"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                "Dialogue: 0,0:00:02.00,0:00:04.00,Top,,0,0,0,,{\\k100}{\\kf50}Jen{\\kf50}ny ｛",
+                "Dialogue: 0,0:00:03.00,0:00:05.00,Bottom,,0,0,0,,{\\k100}{\\kf100}8675",
+                // Waits for the first line to be sung before taking its row
+                "Dialogue: 0,0:00:04.00,0:00:06.00,Top,,0,0,0,,{\\k100}{\\kf100}309",
+            ]
+        );
+
+        let options = options.two_lines(false).effect(KaraokeEffect::Switch);
+        let ass = to_ass(&lines, None, &options);
+        assert!(ass.contains("Dialogue: 0,0:00:04.00,0:00:05.00,Lyrics,,0,0,0,,{\\k100}8675\n"));
+    }
+
+    #[test]
+    fn test_ass() {
+        let file = sample();
+        let ass = file.to_ass(&AssOptions::default()).unwrap();
+        assert!(ass.starts_with("[Script Info]\nTitle: 8675309Jenny Jenny - Tommy Tutone\n"));
+        assert!(ass.contains("Style: Top,Tahoma,48,&H00FF0000,&H00FFFFFF,"));
+        assert!(ass.contains("{\\kf"));
+        let sung = file
+            .lyrics()
+            .unwrap()
+            .lines()
+            .iter()
+            .filter(|l| !l.trim().is_empty())
+            .count();
+        assert_eq!(ass.matches("\nDialogue:").count(), sung);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::error::{EmkError, Result};
+use crate::lyrics::Lyrics;
+
+/// Decoded contents of `CURSOR_DATA`, an NCN cursor file.
+///
+/// The file is a list of little-endian u16 timings, one for every cell of the sung lyric lines,
+/// telling when the highlight reaches that cell. Timings are in cursor units, of which there are
+/// [`Cursor::UNITS_PER_QUARTER`] per quarter note of the MIDI file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub values: Vec<u16>,
+    /// Bytes after the last complete value, kept so the file can be written back as it was
+    pub trailer: Vec<u8>,
+}
+
+impl Cursor {
+    /// Cursor units per quarter note
+    pub const UNITS_PER_QUARTER: u32 = 24;
+
+    pub fn parse(data: &[u8]) -> Self {
+        let chunks = data.chunks_exact(2);
+        let trailer = chunks.remainder().to_vec();
+        Self {
+            values: chunks.map(|b| u16::from_le_bytes([b[0], b[1]])).collect(),
+            trailer,
+        }
+    }
+
+    pub fn values(&self) -> &[u16] {
+        &self.values
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self
+            .values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<u8>>();
+        data.extend_from_slice(&self.trailer);
+        data
+    }
+
+    /// Converts a cursor value to MIDI ticks, for a file with `ppq` ticks per quarter note
+    pub fn to_ticks(value: u16, ppq: u16) -> u32 {
+        value as u32 * ppq as u32 / Self::UNITS_PER_QUARTER
+    }
+
+    /// Checks that there is a timing for every cell of the lyrics.
+    ///
+    /// Files often have a few timings more than there are cells, which are ignored.
+    pub fn validate(&self, lyrics: &Lyrics) -> Result<()> {
+        let cells = lyrics.cell_count();
+        if self.values.len() < cells {
+            return Err(EmkError::CursorMismatch {
+                cells,
+                values: self.values.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::lyrics::Lyrics;
+    use crate::test_util::sample;
+
+    #[test]
+    fn test_parse_cursor() {
+        let file = sample();
+        let cursor = file.cursor().unwrap();
+        assert_eq!(cursor.values().len(), 1209);
+        assert_eq!(&cursor.values()[..3], [11, 16, 16]);
+        assert!(cursor.values().windows(2).all(|w| w[0] <= w[1]));
+        cursor.validate(&file.lyrics().unwrap()).unwrap();
+
+        let raw = file.get_data("CURSOR_DATA").unwrap().data.to_bytes();
+        assert_eq!(cursor.to_bytes(), raw);
+    }
+
+    #[test]
+    fn test_cursor_too_short() {
+        let lyrics = Lyrics::decode(b"Title\r\nArtist\r\nC\r\n\r\nabc");
+        let cursor = Cursor::parse(&[1, 0, 2, 0]);
+        assert!(cursor.validate(&lyrics).is_err());
+    }
+}
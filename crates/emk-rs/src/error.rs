@@ -0,0 +1,243 @@
+use std::fmt;
+
+/// Errors returned by emk-rs.
+///
+/// Offsets are absolute byte offsets into the decrypted file, and `tag` is the name of the tag
+/// being read when the error occurred, if it was known at that point.
+#[derive(Debug)]
+pub enum EmkError {
+    /// An I/O error from reading or writing a file
+    Io(std::io::Error),
+    /// The key does not decrypt the file magic
+    InvalidKey,
+    /// No key could be recovered for the file
+    KeyNotFound,
+    /// Cracking was cancelled through [`CrackOptions::cancel`](crate::util::CrackOptions::cancel)
+    Cancelled,
+    /// Cracking took longer than [`CrackOptions::timeout`](crate::util::CrackOptions::timeout)
+    TimedOut,
+    /// The data ended before a field could be read
+    Truncated { tag: Option<String>, offset: usize },
+    /// The `SFDS` magic of a tag entry is missing
+    InvalidMagic { tag: Option<String>, offset: usize },
+    /// A field has a data type byte that is not known
+    UnknownDataType {
+        tag: Option<String>,
+        offset: usize,
+        byte: u8,
+    },
+    /// A field has a known data type, but not the one expected for it
+    InvalidDataType {
+        tag: Option<String>,
+        offset: usize,
+        field: &'static str,
+    },
+    /// A string field is not valid UTF-8
+    InvalidUtf8 { tag: Option<String>, offset: usize },
+    /// A range points outside of the file
+    InvalidRange {
+        tag: Option<String>,
+        begin: usize,
+        end: usize,
+    },
+    /// The compressed data of a tag could not be inflated
+    Decompress {
+        tag: String,
+        offset: usize,
+        source: std::io::Error,
+    },
+    /// A tag required for the operation is not in the file
+    MissingTag(String),
+    /// A required field of a text tag is missing
+    MissingField { tag: String, field: String },
+    /// A field of a text tag has a value that cannot be parsed
+    InvalidField {
+        tag: String,
+        field: String,
+        value: String,
+    },
+    /// A field value cannot be converted to the requested type
+    InvalidConversion { value: String, target: &'static str },
+    /// A value does not fit in the field it is written to
+    ValueTooLarge { tag: String, field: &'static str },
+    /// The preamble is too short to hold the file header
+    InvalidPreamble,
+    /// A line of a key ring file is not a hex-encoded key
+    InvalidKeyRing { line: usize, value: String },
+    /// A character cannot be represented in the text encoding of a tag
+    UnencodableChar { ch: char, encoding: &'static str },
+    /// The cursor has fewer timings than there are lyric cells
+    CursorMismatch { cells: usize, values: usize },
+    /// `MIDI_DATA` is not a valid Standard MIDI File
+    InvalidMidi { offset: usize, reason: &'static str },
+    /// A SoundFont is not a valid SF2 file
+    InvalidSoundFont { offset: usize, reason: &'static str },
+}
+
+pub type Result<T> = std::result::Result<T, EmkError>;
+
+impl EmkError {
+    /// Name of the tag the error occurred in, if known
+    pub fn tag(&self) -> Option<&str> {
+        match self {
+            EmkError::Truncated { tag, .. }
+            | EmkError::InvalidMagic { tag, .. }
+            | EmkError::UnknownDataType { tag, .. }
+            | EmkError::InvalidDataType { tag, .. }
+            | EmkError::InvalidUtf8 { tag, .. }
+            | EmkError::InvalidRange { tag, .. } => tag.as_deref(),
+            EmkError::Decompress { tag, .. }
+            | EmkError::MissingTag(tag)
+            | EmkError::MissingField { tag, .. }
+            | EmkError::InvalidField { tag, .. }
+            | EmkError::ValueTooLarge { tag, .. } => Some(tag),
+            _ => None,
+        }
+    }
+
+    /// Byte offset the error occurred at, if known
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            EmkError::Truncated { offset, .. }
+            | EmkError::InvalidMagic { offset, .. }
+            | EmkError::UnknownDataType { offset, .. }
+            | EmkError::InvalidDataType { offset, .. }
+            | EmkError::InvalidUtf8 { offset, .. }
+            | EmkError::Decompress { offset, .. } => Some(*offset),
+            EmkError::InvalidRange { begin, .. } => Some(*begin),
+            _ => None,
+        }
+    }
+
+    /// Whether the error is caused by the key, meaning another key might succeed
+    pub fn is_key_error(&self) -> bool {
+        matches!(self, EmkError::InvalidKey | EmkError::KeyNotFound)
+    }
+}
+
+/// Formats the `in tag X` suffix of an error message
+struct InTag<'a>(&'a Option<String>);
+
+impl fmt::Display for InTag<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(tag) => write!(f, " in tag {tag}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for EmkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmkError::Io(e) => write!(f, "I/O error: {e}"),
+            EmkError::InvalidKey => write!(f, "Invalid key: magic does not match"),
+            EmkError::KeyNotFound => write!(f, "No valid key found"),
+            EmkError::Cancelled => write!(f, "Cracking cancelled"),
+            EmkError::TimedOut => write!(f, "Cracking timed out"),
+            EmkError::Truncated { tag, offset } => {
+                write!(f, "Data truncated at offset {offset:#x}{}", InTag(tag))
+            }
+            EmkError::InvalidMagic { tag, offset } => {
+                write!(f, "Magic check failed at offset {offset:#x}{}", InTag(tag))
+            }
+            EmkError::UnknownDataType { tag, offset, byte } => write!(
+                f,
+                "Unknown data type {byte:#04x} at offset {offset:#x}{}",
+                InTag(tag)
+            ),
+            EmkError::InvalidDataType { tag, offset, field } => write!(
+                f,
+                "Invalid data type for {field} at offset {offset:#x}{}",
+                InTag(tag)
+            ),
+            EmkError::InvalidUtf8 { tag, offset } => {
+                write!(f, "Invalid UTF-8 at offset {offset:#x}{}", InTag(tag))
+            }
+            EmkError::InvalidRange { tag, begin, end } => {
+                write!(f, "Invalid range {begin:#x}..{end:#x}{}", InTag(tag))
+            }
+            EmkError::Decompress {
+                tag,
+                offset,
+                source,
+            } => write!(
+                f,
+                "Failed to decompress tag {tag} at offset {offset:#x}: {source}"
+            ),
+            EmkError::MissingTag(tag) => write!(f, "Missing tag {tag}"),
+            EmkError::MissingField { tag, field } => {
+                write!(f, "Missing field {field} in tag {tag}")
+            }
+            EmkError::InvalidField { tag, field, value } => {
+                write!(f, "Invalid value {value:?} for field {field} in tag {tag}")
+            }
+            EmkError::InvalidConversion { value, target } => {
+                write!(f, "Cannot convert {value} to {target}")
+            }
+            EmkError::ValueTooLarge { tag, field } => {
+                write!(f, "Value of {field} too large in tag {tag}")
+            }
+            EmkError::InvalidPreamble => write!(f, "Preamble too short"),
+            EmkError::InvalidKeyRing { line, value } => {
+                write!(f, "Invalid key {value:?} on line {line} of key ring")
+            }
+            EmkError::UnencodableChar { ch, encoding } => {
+                write!(f, "Cannot encode {ch:?} in {encoding}")
+            }
+            EmkError::CursorMismatch { cells, values } => {
+                write!(f, "Cursor has {values} timings for {cells} lyric cells")
+            }
+            EmkError::InvalidMidi { offset, reason } => {
+                write!(f, "Invalid MIDI data at offset {offset:#x}: {reason}")
+            }
+            EmkError::InvalidSoundFont { offset, reason } => {
+                write!(f, "Invalid SoundFont at offset {offset:#x}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmkError::Io(e) | EmkError::Decompress { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for EmkError {
+    fn from(e: std::io::Error) -> Self {
+        EmkError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmkError;
+    use crate::test_util::TEST_DATA;
+    use crate::types::EmkFile;
+
+    #[test]
+    fn test_wrong_key() {
+        let err = EmkFile::from_bytes_with_key(TEST_DATA, &[0x12, 0x34]).unwrap_err();
+        assert!(err.is_key_error());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let err = EmkFile::from_bytes(&TEST_DATA[..0x30]).unwrap_err();
+        assert!(matches!(err, EmkError::Truncated { offset: 0x2a, .. }));
+        assert!(!err.is_key_error());
+    }
+
+    #[test]
+    fn test_corrupt_data() {
+        let mut data = TEST_DATA.to_vec();
+        // Flip a byte inside the compressed MIDI data
+        data[0x200] ^= 0xFF;
+        let err = EmkFile::from_bytes(&data).unwrap_err();
+        assert_eq!(err.tag(), Some("MIDI_DATA"));
+    }
+}
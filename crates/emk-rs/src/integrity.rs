@@ -0,0 +1,236 @@
+use md5::{Digest, Md5};
+
+use crate::error::EmkError;
+use crate::types::{EmkFile, EmkReader, TagEntry, NEW_PREAMBLE};
+
+/// Result of checking every tag of a file against its tag table entry
+#[derive(Debug)]
+pub struct IntegrityReport {
+    pub tags: Vec<TagIntegrity>,
+}
+
+impl IntegrityReport {
+    /// Whether every tag passed all checks
+    pub fn is_ok(&self) -> bool {
+        self.tags.iter().all(TagIntegrity::is_ok)
+    }
+
+    /// Tags that failed at least one check
+    pub fn damaged(&self) -> impl Iterator<Item = &TagIntegrity> {
+        self.tags.iter().filter(|t| !t.is_ok())
+    }
+}
+
+/// Integrity of a single tag.
+///
+/// The embedded MD5 hash and the uncompressed size both describe the *inflated* payload.
+#[derive(Debug)]
+pub struct TagIntegrity {
+    pub tag: String,
+    /// MD5 hash stored in the tag table
+    pub expected_hash: [u8; 16],
+    /// MD5 hash of the payload, if it could be read
+    pub actual_hash: Option<[u8; 16]>,
+    /// Uncompressed size stored in the tag table
+    pub expected_size: u64,
+    /// Size of the payload, if it could be read
+    pub actual_size: Option<u64>,
+    /// Whether the compressed data range lies inside the file's data area
+    pub offsets_in_range: bool,
+    /// Why the payload could not be read
+    pub error: Option<EmkError>,
+}
+
+impl TagIntegrity {
+    fn new(
+        tag: &str,
+        expected_hash: [u8; 16],
+        expected_size: u64,
+        offsets_in_range: bool,
+        payload: Result<Vec<u8>, EmkError>,
+    ) -> Self {
+        let (actual_hash, actual_size, error) = match payload {
+            Ok(data) => (
+                Some(Md5::digest(&data).into()),
+                Some(data.len() as u64),
+                None,
+            ),
+            Err(e) => (None, None, Some(e)),
+        };
+        Self {
+            tag: tag.to_string(),
+            expected_hash,
+            actual_hash,
+            expected_size,
+            actual_size,
+            offsets_in_range,
+            error,
+        }
+    }
+
+    pub fn hash_ok(&self) -> bool {
+        self.actual_hash == Some(self.expected_hash)
+    }
+
+    pub fn size_ok(&self) -> bool {
+        self.actual_size == Some(self.expected_size)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.hash_ok() && self.size_ok() && self.offsets_in_range && self.error.is_none()
+    }
+}
+
+/// Offset of the u64 pointer to the start of the tag table
+const HEADER_POS_OFFSET: usize = 0x22;
+/// Offset of the first payload, right after the file header holding the magic, the pointers to the
+/// tag table, its hash and the fields that follow it
+const DATA_START: u64 = NEW_PREAMBLE.len() as u64;
+
+/// Whether the compressed data of each entry lies within `data_start..data_end` without
+/// overlapping the data of another entry
+fn ranges_in_bounds(entries: &[&TagEntry], data_start: u64, data_end: u64) -> Vec<bool> {
+    let overlaps =
+        |a: &TagEntry, b: &TagEntry| a.data_begin < b.data_end && b.data_begin < a.data_end;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            entry.data_begin <= entry.data_end
+                && entry.data_begin >= data_start
+                && entry.data_end <= data_end
+                && !entries
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| i != j && overlaps(entry, other))
+        })
+        .collect()
+}
+
+/// Checks every tag of a file that has not been parsed yet
+pub(crate) fn verify_reader(reader: &EmkReader) -> IntegrityReport {
+    let entries = reader.entries().iter().collect::<Vec<_>>();
+    let in_range = ranges_in_bounds(&entries, DATA_START, reader.header_pos() as u64);
+    let tags = entries
+        .iter()
+        .zip(in_range)
+        .map(|(entry, in_range)| {
+            TagIntegrity::new(
+                &entry.tag,
+                entry.md5_hash,
+                entry.uncompressed_size,
+                in_range,
+                reader.inflate(entry),
+            )
+        })
+        .collect();
+    IntegrityReport { tags }
+}
+
+/// Checks the payloads of a parsed file against the hashes and sizes it was read with
+pub(crate) fn verify_file(file: &EmkFile) -> IntegrityReport {
+    // The payloads end where the tag table starts, which the preamble points to
+    let header_pos = file
+        .preamble
+        .get(HEADER_POS_OFFSET..HEADER_POS_OFFSET + 8)
+        .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()));
+    let entries = file.tags.iter().map(|data| &data.entry).collect::<Vec<_>>();
+    // The preamble ends where the first payload starts, so it cannot bound them
+    let in_range = ranges_in_bounds(&entries, DATA_START, header_pos);
+    let tags = file
+        .tags
+        .iter()
+        .zip(in_range)
+        .map(|(data, in_range)| {
+            let entry = &data.entry;
+            TagIntegrity::new(
+                &entry.tag,
+                entry.md5_hash,
+                entry.uncompressed_size,
+                in_range,
+                Ok(data.data.to_bytes()),
+            )
+        })
+        .collect();
+    IntegrityReport { tags }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{sample, TEST_DATA};
+    use crate::types::{EmkReader, TagData};
+    use crate::util::{xor, EMK_MAGIC};
+
+    #[test]
+    fn test_verify_ok() {
+        let reader = EmkReader::decrypt_default_key(TEST_DATA).unwrap();
+        assert!(reader.verify().is_ok());
+
+        let file = sample();
+        assert!(file.verify().is_ok());
+    }
+
+    #[test]
+    fn test_verify_damaged_midi() {
+        let mut data = xor(TEST_DATA, &EMK_MAGIC.to_be_bytes()).unwrap();
+        data[0x200] ^= 0xFF;
+
+        let report = EmkReader::new(data).unwrap().verify();
+        let damaged = report.damaged().map(|t| t.tag.as_str()).collect::<Vec<_>>();
+        assert_eq!(damaged, ["MIDI_DATA"]);
+    }
+
+    #[test]
+    fn test_verify_edited_file() {
+        let mut file = sample();
+        if let TagData::SongInfo(s) = &mut file.get_data_mut("SONG_INFO").unwrap().data {
+            s.title = "Jenny Jenny".to_string();
+        }
+
+        let report = file.verify();
+        let song_info = report.damaged().next().unwrap();
+        assert_eq!(song_info.tag, "SONG_INFO");
+        assert!(!song_info.hash_ok());
+    }
+
+    #[test]
+    fn test_verify_offsets() {
+        let mut file = sample();
+        let header_pos = u64::from_le_bytes(file.preamble[0x22..0x2a].try_into().unwrap());
+        // The last payload running into the tag table
+        let last = file.tags.len() - 1;
+        file.tags[last].entry.data_end = header_pos + 1;
+        // A payload overlapping the one before it
+        file.tags[1].entry.data_begin = file.tags[0].entry.data_end - 1;
+
+        let report = file.verify();
+        let out_of_range = report
+            .tags
+            .iter()
+            .filter(|t| !t.offsets_in_range)
+            .map(|t| t.tag.as_str())
+            .collect::<Vec<_>>();
+        let tag = |i: usize| file.tags[i].entry.tag.as_str();
+        assert_eq!(out_of_range, [tag(0), tag(1), tag(last)]);
+    }
+
+    #[test]
+    fn test_verify_offsets_in_header() {
+        let mut data = xor(TEST_DATA, &EMK_MAGIC.to_be_bytes()).unwrap();
+        let header_pos = u64::from_le_bytes(data[0x22..0x2a].try_into().unwrap()) as usize;
+        // The HEADER payload starting in the middle of the file header
+        assert_eq!(data[header_pos + 16..header_pos + 18], [0x02, 0x65]);
+        data[header_pos + 17] = 0x10;
+
+        let report = EmkReader::new(data).unwrap().verify();
+        let out_of_range = report.tags.iter().filter(|t| !t.offsets_in_range);
+        assert_eq!(
+            out_of_range.map(|t| t.tag.as_str()).collect::<Vec<_>>(),
+            ["HEADER"]
+        );
+
+        let mut file = sample();
+        file.tags[0].entry.data_begin = 0x10;
+        assert!(!file.verify().tags[0].offsets_in_range);
+    }
+}
@@ -0,0 +1,402 @@
+use crate::error::{EmkError, Result};
+use crate::lyrics::LyricEncoding;
+use crate::midi::{EventKind, Meta, MidiSong, Track};
+use crate::timed::{TimedLine, TimedSyllable};
+use crate::types::SongInfo;
+
+/// Options of [`to_kar`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KarOptions {
+    lyric_events: bool,
+}
+
+impl KarOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also writes every syllable as a lyric meta event, for players that only read those. Players
+    /// that show both kinds of events then show the lyrics twice, so this is off by default.
+    pub fn lyric_events(mut self, lyric_events: bool) -> Self {
+        self.lyric_events = lyric_events;
+        self
+    }
+}
+
+/// Builds the `Words` track of a Soft Karaoke file from timed lines in MIDI ticks.
+///
+/// Every syllable gets a text event, where a leading `\` starts a new paragraph and `/` a new
+/// line. With [`KarOptions::lyric_events`], it also gets a lyric event with the same text, where a
+/// trailing `\r` ends the line.
+fn words_track(
+    lines: &[TimedLine],
+    song: Option<&SongInfo>,
+    encoding: LyricEncoding,
+    options: &KarOptions,
+) -> Result<Track> {
+    let mut events = vec![
+        Meta::TrackName(b"Words".to_vec()),
+        Meta::Text(b"@KMIDI KARAOKE FILE".to_vec()),
+        Meta::Text(b"@V0100".to_vec()),
+    ];
+    if let Some(song) = song {
+        let language = song.language.trim().to_uppercase();
+        let language = language.chars().take(4).collect::<String>();
+        if !language.is_empty() {
+            events.push(Meta::Text(format!("@L{}", language).into_bytes()));
+        }
+        // A title the encoding cannot hold should not fail the whole export
+        events.push(Meta::Text(
+            encoding.encode_lossy(&format!("@T{}", song.title.trim())),
+        ));
+        events.push(Meta::Text(
+            encoding.encode_lossy(&format!("@T{}", song.artist.trim())),
+        ));
+    }
+    let mut events = events.into_iter().map(|meta| (0, meta)).collect::<Vec<_>>();
+
+    let mut paragraph = true;
+    for line in lines {
+        if line.text().trim().is_empty() {
+            paragraph = true;
+            continue;
+        }
+        let last = line.syllables.len() - 1;
+        for (i, syllable) in line.syllables.iter().enumerate() {
+            let text = encoding.encode(&syllable.text)?;
+            let marker: &[u8] = match i {
+                0 if paragraph => b"\\",
+                0 => b"/",
+                _ => b"",
+            };
+            events.push((syllable.start, Meta::Text([marker, &text[..]].concat())));
+            if options.lyric_events {
+                let end: &[u8] = if i == last { b"\r" } else { b"" };
+                events.push((syllable.start, Meta::Lyric([&text[..], end].concat())));
+            }
+        }
+        paragraph = false;
+    }
+    Ok(Track::from_timed_events(
+        events
+            .into_iter()
+            .map(|(tick, meta)| (tick, EventKind::Meta(meta))),
+    ))
+}
+
+/// Adds a lyrics track to a Standard MIDI File, turning it into a Soft Karaoke `.kar` file.
+///
+/// `lines` are timed in ticks of the MIDI file. The tracks of the MIDI file are kept as they are,
+/// and a format 0 file becomes format 1 to make room for the new track.
+pub fn to_kar(
+    midi: &[u8],
+    lines: &[TimedLine],
+    song: Option<&SongInfo>,
+    encoding: LyricEncoding,
+    options: &KarOptions,
+) -> Result<Vec<u8>> {
+    let mut midi = MidiSong::parse(midi)?;
+    if midi.format == 2 {
+        return Err(EmkError::InvalidMidi {
+            offset: 8,
+            reason: "format 2 files are not supported",
+        });
+    }
+    midi.format = 1;
+    midi.tracks
+        .push(words_track(lines, song, encoding, options)?);
+    Ok(midi.to_bytes())
+}
+
+/// A text meta event of a MIDI file
+struct TextEvent<'a> {
+    track: usize,
+    tick: u32,
+    kind: u8,
+    data: &'a [u8],
+}
+
+fn text_events(song: &MidiSong) -> Vec<TextEvent<'_>> {
+    let mut events = Vec::new();
+    for (track, events_of_track) in song.tracks.iter().enumerate() {
+        for (tick, meta) in events_of_track.meta_events() {
+            if let Meta::Text(data) | Meta::TrackName(data) | Meta::Lyric(data) = meta {
+                events.push(TextEvent {
+                    track,
+                    tick,
+                    kind: meta.kind(),
+                    data,
+                });
+            }
+        }
+    }
+    events
+}
+
+/// The track and kind of meta event the lyrics are in: Soft Karaoke text events when the file has
+/// them and lyric events otherwise, in the track with the most of them
+fn lyric_source(events: &[TextEvent]) -> Result<(usize, u8)> {
+    let soft_karaoke = events
+        .iter()
+        .any(|e| e.kind == Meta::TEXT && e.data.starts_with(b"@K"));
+    let kind = if soft_karaoke {
+        Meta::TEXT
+    } else {
+        Meta::LYRIC
+    };
+
+    let mut counts = std::collections::BTreeMap::new();
+    for event in events {
+        if event.kind == kind && !event.data.starts_with(b"@") {
+            *counts.entry(event.track).or_insert(0) += 1;
+        }
+    }
+    let track = counts
+        .into_iter()
+        .max_by_key(|&(track, count)| (count, std::cmp::Reverse(track)))
+        .map(|(track, _)| track)
+        .ok_or(EmkError::InvalidMidi {
+            offset: 0,
+            reason: "no lyric events",
+        })?;
+    Ok((track, kind))
+}
+
+/// Removes the lyrics [`read_kar`] reads from a karaoke MIDI file, so they are not left in
+/// `MIDI_DATA` next to `LYRIC_DATA`.
+///
+/// The lyric events and Soft Karaoke `@` headers are removed, along with the lyric events that
+/// Soft Karaoke files may repeat the text events with. A lyrics track left with nothing but its
+/// name is removed altogether.
+pub fn strip_kar(midi: &[u8]) -> Result<Vec<u8>> {
+    let mut song = MidiSong::parse(midi)?;
+    let (track, kind) = lyric_source(&text_events(&song))?;
+
+    for (i, events) in song.tracks.iter_mut().enumerate() {
+        events.retain_events(|event| match &event.kind {
+            EventKind::Meta(Meta::Text(data)) if data.starts_with(b"@") => false,
+            EventKind::Meta(Meta::Lyric(_)) if i == track => false,
+            EventKind::Meta(Meta::Text(_)) if i == track => kind != Meta::TEXT,
+            _ => true,
+        });
+    }
+    let words_only = song.tracks[track].events.iter().all(|event| {
+        matches!(
+            event.kind,
+            EventKind::Meta(Meta::TrackName(_) | Meta::EndOfTrack)
+        )
+    });
+    if words_only && song.tracks.len() > 1 {
+        song.tracks.remove(track);
+    }
+    Ok(song.to_bytes())
+}
+
+/// Lyrics read from a karaoke MIDI file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KarLyrics {
+    pub title: String,
+    pub artist: String,
+    /// Lines timed in MIDI ticks. Empty lines separate paragraphs.
+    pub lines: Vec<TimedLine>,
+}
+
+/// Reads the lyrics of a `.kar` file or of a MIDI file with lyric events.
+///
+/// Soft Karaoke text events are used when the file has them, and lyric events otherwise. In
+/// both, a leading `\` starts a new paragraph, and a leading `/` or a `\r` or `\n` at either end
+/// of the text starts a new line.
+pub fn read_kar(midi: &[u8]) -> Result<KarLyrics> {
+    let song = MidiSong::parse(midi)?;
+    let events = text_events(&song);
+    let (track, kind) = lyric_source(&events)?;
+    let is_lyric = |e: &&TextEvent| e.kind == kind && !e.data.starts_with(b"@");
+
+    let raw = events
+        .iter()
+        .filter(is_lyric)
+        .filter(|e| e.track == track)
+        .flat_map(|e| e.data.iter().copied())
+        .collect::<Vec<u8>>();
+    let encoding = LyricEncoding::detect(&raw);
+    let decode = |data: &[u8]| encoding.decode(data);
+
+    let mut kar = KarLyrics::default();
+    let headers = events
+        .iter()
+        .filter(|e| e.kind == Meta::TEXT)
+        .filter_map(|e| e.data.strip_prefix(b"@T"));
+    for (i, header) in headers.take(2).enumerate() {
+        let header = decode(header).trim().to_string();
+        match i {
+            0 => kar.title = header,
+            _ => kar.artist = header,
+        }
+    }
+    if kar.title.is_empty() {
+        if let Some(name) = events
+            .iter()
+            .find(|e| e.track == 0 && e.kind == Meta::TRACK_NAME)
+        {
+            kar.title = decode(name.data).trim().to_string();
+        }
+    }
+
+    let mut lines = vec![Vec::new()];
+    let mut break_next = false;
+    for event in events.iter().filter(is_lyric).filter(|e| e.track == track) {
+        let mut text = decode(event.data);
+        let paragraph = text.starts_with('\\');
+        if paragraph || text.starts_with(['/', '\r', '\n']) || break_next {
+            if !lines.last().is_some_and(Vec::is_empty) {
+                lines.push(Vec::new());
+            }
+            // An empty line separates paragraphs, except at the start of the song
+            if paragraph && lines.len() > 1 {
+                lines.insert(lines.len() - 1, Vec::new());
+            }
+        }
+        break_next = text.ends_with(['\r', '\n']);
+        text.retain(|ch| ch != '\r' && ch != '\n');
+        let text = text.trim_start_matches(['\\', '/']);
+        if !text.is_empty() {
+            lines
+                .last_mut()
+                .unwrap()
+                .push((event.tick, text.to_string()));
+        }
+    }
+    if lines.last().is_some_and(Vec::is_empty) {
+        lines.pop();
+    }
+
+    let starts = lines
+        .iter()
+        .flatten()
+        .map(|&(tick, _)| tick)
+        .collect::<Vec<_>>();
+    let mut index = 0;
+    kar.lines = lines
+        .into_iter()
+        .map(|line| TimedLine {
+            syllables: line
+                .into_iter()
+                .map(|(start, text)| {
+                    index += 1;
+                    let end = starts.get(index).copied().unwrap_or(start);
+                    TimedSyllable {
+                        text,
+                        start,
+                        end: end.max(start),
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(kar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_kar, KarOptions};
+    use crate::midi::{Meta, MidiSong};
+    use crate::test_util::sample;
+    use crate::timebase::TimeBase;
+    use crate::types::{EmkFile, SongInfo};
+
+    #[test]
+    fn test_kar() {
+        let file = sample();
+        let kar = file.to_kar().unwrap();
+        assert_eq!(&kar[8..12], [0, 1, 0, 13]);
+        // The original tracks are kept, so the timing does not change
+        assert_eq!(
+            TimeBase::from_midi(&kar).unwrap(),
+            file.time_base().unwrap()
+        );
+
+        let words = &kar[kar.windows(4).rposition(|w| w == b"MTrk").unwrap()..];
+        let contains = |needle: &[u8]| words.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"\x03\x05Words"));
+        assert!(contains(b"@KMIDI KARAOKE FILE"));
+        assert!(contains(b"@LTHAI"));
+        assert!(contains(b"@T8675309Jenny Jenny"));
+        assert!(contains(b"@TTommy Tutone"));
+        assert!(contains(b"\x01\x02\\N"));
+        assert!(contains(b"\x01\x02/>"));
+        assert!(!contains(b"\xFF\x05"));
+        assert!(words.ends_with(&[0xFF, 0x2F, 0x00]));
+
+        let kar = file
+            .to_kar_with_options(&KarOptions::new().lyric_events(true))
+            .unwrap();
+        let words = &kar[kar.windows(4).rposition(|w| w == b"MTrk").unwrap()..];
+        assert!(words.windows(4).any(|w| w == b"\x05\x02?\r"));
+    }
+
+    #[test]
+    fn test_kar_thai_title() {
+        let mut file = sample();
+        let text = file.song_info().unwrap().to_kv();
+        let (before, after) = text.split_once("TITLE=8675309Jenny Jenny").unwrap();
+        // "เพลง" in TIS-620, then the unassigned byte 0xDB
+        let title = b"TITLE=\xE0\xBE\xC5\xA7\xDB";
+        let raw = [before.as_bytes(), title, after.as_bytes()].concat();
+        *file.song_info_mut().unwrap() = SongInfo::from_kv_bytes(&raw).unwrap();
+        file.song_info_mut().unwrap().artist = "日本".to_string();
+
+        let kar = file.to_kar().unwrap();
+        let words = &kar[kar.windows(4).rposition(|w| w == b"MTrk").unwrap()..];
+        let contains = |needle: &[u8]| words.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"@T\xE0\xBE\xC5\xA7?"));
+        assert!(contains(b"@T??"));
+    }
+
+    #[test]
+    fn test_kar_round_trip() {
+        let file = sample();
+        let kar = file.to_kar().unwrap();
+        let lyrics = read_kar(&kar).unwrap();
+        assert_eq!(lyrics.title, "8675309Jenny Jenny");
+        assert_eq!(lyrics.artist, "Tommy Tutone");
+
+        let imported = EmkFile::from_kar_bytes("000001", kar).unwrap();
+        let sung = |file: &EmkFile| {
+            file.timed_lyrics()
+                .unwrap()
+                .into_iter()
+                .filter(|line| !line.text().trim().is_empty())
+                .map(|line| (line.text(), line.start()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sung(&imported), sung(&file));
+        assert_eq!(imported.song_info().unwrap().title, "8675309Jenny Jenny");
+        assert!(imported.to_bytes().is_ok());
+    }
+
+    #[test]
+    fn test_kar_emk_kar_round_trip() {
+        let text_events = |kar: &[u8]| {
+            let song = MidiSong::parse(kar).unwrap();
+            let count = song
+                .tracks
+                .iter()
+                .flat_map(|t| t.meta_events())
+                .filter(|(_, meta)| matches!(meta, Meta::Text(_) | Meta::Lyric(_)))
+                .count();
+            (song.tracks.len(), count)
+        };
+        let file = sample();
+        let options = KarOptions::new().lyric_events(true);
+        let kar = file.to_kar_with_options(&options).unwrap();
+
+        let imported = EmkFile::from_kar_bytes("000001", kar.clone()).unwrap();
+        // The lyrics live in LYRIC_DATA only, so MIDI_DATA is back to the original tracks
+        assert_eq!(
+            imported.get_data("MIDI_DATA").unwrap().data.to_bytes(),
+            file.get_data("MIDI_DATA").unwrap().data.to_bytes()
+        );
+        let again = imported.to_kar_with_options(&options).unwrap();
+        assert_eq!(text_events(&again), text_events(&kar));
+    }
+}
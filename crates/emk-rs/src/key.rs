@@ -0,0 +1,149 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::EmkError;
+
+/// Names of the major keys by pitch class, spelled the way key signatures usually are
+const MAJOR_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+const MINOR_NAMES: [&str; 12] = [
+    "Cm", "C#m", "Dm", "Ebm", "Em", "Fm", "F#m", "Gm", "G#m", "Am", "Bbm", "Bm",
+];
+
+/// Key of a song, like the `KEY` of `SONG_INFO`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    /// Pitch class of the tonic, 0 for C to 11 for B
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+impl Key {
+    pub fn transpose(self, semitones: i32) -> Self {
+        Self {
+            tonic: (self.tonic as i32 + semitones).rem_euclid(12) as u8,
+            minor: self.minor,
+        }
+    }
+
+    /// The key of a MIDI key signature, given as sharps (positive) or flats (negative)
+    pub fn from_signature(sharps: i8, minor: bool) -> Self {
+        // Every sharp moves the major tonic up a fifth, and the minor tonic is a minor third
+        // below it
+        let major = (sharps as i32 * 7).rem_euclid(12);
+        let tonic = if minor { major + 9 } else { major };
+        Self {
+            tonic: (tonic % 12) as u8,
+            minor,
+        }
+    }
+
+    /// Sharps (positive) or flats (negative) of the key signature, preferring the signature with
+    /// fewer accidentals and sharps when both have six
+    pub fn signature(self) -> i8 {
+        let major = if self.minor {
+            (self.tonic + 3) % 12
+        } else {
+            self.tonic
+        };
+        // 7 is its own inverse modulo 12, so this undoes `from_signature`
+        let sharps = (major as i32 * 7) % 12;
+        if sharps > 6 {
+            (sharps - 12) as i8
+        } else {
+            sharps as i8
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = if self.minor {
+            &MINOR_NAMES
+        } else {
+            &MAJOR_NAMES
+        };
+        f.write_str(names[self.tonic as usize % 12])
+    }
+}
+
+impl FromStr for Key {
+    type Err = EmkError;
+
+    /// Parses names like `F#m`, `Bb` or `C#min`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EmkError::InvalidField {
+            tag: "SONG_INFO".to_string(),
+            field: "KEY".to_string(),
+            value: s.to_string(),
+        };
+        let mut chars = s.trim().chars();
+        let natural: i32 = match chars.next().map(|ch| ch.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(invalid()),
+        };
+        let rest = chars.as_str();
+        let (accidental, rest) = match rest.chars().next() {
+            Some('#' | '♯') => (1, &rest[rest.chars().next().unwrap().len_utf8()..]),
+            Some('b' | '♭') => (-1, &rest[rest.chars().next().unwrap().len_utf8()..]),
+            _ => (0, rest),
+        };
+        let minor = match rest.trim().to_ascii_lowercase().as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            tonic: (natural + accidental).rem_euclid(12) as u8,
+            minor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Key;
+
+    #[test]
+    fn test_parse_key() {
+        let key: Key = "F#m".parse().unwrap();
+        assert_eq!(
+            key,
+            Key {
+                tonic: 6,
+                minor: true
+            }
+        );
+        assert_eq!(key.transpose(2).to_string(), "G#m");
+        assert_eq!("Bb".parse::<Key>().unwrap().transpose(-1).to_string(), "A");
+        assert_eq!("Cb".parse::<Key>().unwrap().to_string(), "B");
+        assert!("H".parse::<Key>().is_err());
+        assert!("Cx".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        for sharps in -7..=7 {
+            for minor in [false, true] {
+                let key = Key::from_signature(sharps, minor);
+                // Seven sharps or flats are written with fewer accidentals
+                let expected = match sharps {
+                    7 => -5,
+                    -7 => 5,
+                    -6 => 6,
+                    _ => sharps,
+                };
+                assert_eq!(key.signature(), expected);
+            }
+        }
+        assert_eq!(Key::from_signature(3, true).to_string(), "F#m");
+        assert_eq!(Key::from_signature(-3, false).to_string(), "Eb");
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::error::{EmkError, Result};
+use crate::util::{xor_verify, EMK_MAGIC};
+
+/// A list of known keys, tried in order before falling back to cracking.
+///
+/// Key ring files have one hex-encoded key per line. Empty lines and lines starting with `#` are
+/// ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRing {
+    keys: Vec<Vec<u8>>,
+}
+
+impl KeyRing {
+    /// An empty key ring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A key ring holding only the default key
+    pub fn with_default_key() -> Self {
+        let mut ring = Self::new();
+        ring.add(EMK_MAGIC.to_be_bytes().as_ref());
+        ring
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
+
+    /// Adds a key to the end of the ring, returning false if it was already known
+    pub fn add(&mut self, key: &[u8]) -> bool {
+        if key.is_empty() || self.contains(key) {
+            return false;
+        }
+        self.keys.push(key.to_vec());
+        true
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|k| k == key)
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        &self.keys
+    }
+
+    /// Finds the first key that decrypts the data
+    pub fn find_key(&self, data: &[u8]) -> Option<&[u8]> {
+        self.keys
+            .iter()
+            .find(|key| xor_verify(data, key))
+            .map(Vec::as_slice)
+    }
+}
+
+impl std::str::FromStr for KeyRing {
+    type Err = EmkError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ring = Self::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = hex::decode(line).map_err(|_| EmkError::InvalidKeyRing {
+                line: i + 1,
+                value: line.to_string(),
+            })?;
+            ring.add(&key);
+        }
+        Ok(ring)
+    }
+}
+
+impl fmt::Display for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for key in &self.keys {
+            writeln!(f, "{}", hex::encode_upper(key))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyRing;
+    use crate::test_util::{sample, TEST_DATA};
+    use crate::types::EmkFile;
+
+    #[test]
+    fn test_parse() {
+        let ring: KeyRing = "# default\nAFF24C9CE9EA9943\n\n0102\n0102\n"
+            .parse()
+            .unwrap();
+        assert_eq!(ring.keys().len(), 2);
+        assert_eq!(ring, ring.to_string().parse().unwrap());
+        assert!("xyz".parse::<KeyRing>().is_err());
+    }
+
+    #[test]
+    fn test_open_with_keyring() {
+        let file = sample();
+        let key = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        let data = file.to_bytes_with_key(&key).unwrap();
+
+        let mut ring = KeyRing::with_default_key();
+        assert!(ring.find_key(&data).is_none());
+        let (_, found) = EmkFile::from_bytes_with_keyring(&data, &mut ring).unwrap();
+        assert_eq!(found, key);
+
+        // The recovered key is remembered for the next file
+        assert_eq!(ring.find_key(&data), Some(&key[..]));
+        assert_eq!(ring.find_key(TEST_DATA), Some(&ring.keys()[0][..]));
+    }
+}
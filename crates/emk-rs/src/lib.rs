@@ -1,5 +1,29 @@
+pub mod analysis;
+pub mod ass;
+pub mod cursor;
+pub mod error;
+pub mod integrity;
+pub mod kar;
+pub mod key;
+pub mod keyring;
+pub mod lrc;
+pub mod lyrics;
+pub mod midi;
+pub mod ncn;
+pub mod srt;
+pub mod stream;
+#[cfg(feature = "synth")]
+pub mod synth;
+#[cfg(test)]
+mod test_util;
+pub mod timebase;
+pub mod timed;
 pub mod types;
 pub mod util;
+pub mod vtt;
+pub mod writer;
+
+pub use error::EmkError;
 
 #[test]
 #[tracing_test::traced_test]
@@ -0,0 +1,349 @@
+use std::fmt::Write;
+
+use crate::timed::{TimedLine, TimedSyllable};
+use crate::types::SongInfo;
+
+/// Formats milliseconds as an LRC timestamp, `mm:ss.xx`
+pub fn format_timestamp(ms: u32) -> String {
+    let cs = (ms + 5) / 10;
+    format!("{:02}:{:02}.{:02}", cs / 6000, cs / 100 % 60, cs % 100)
+}
+
+fn write_tags(out: &mut String, song: Option<&SongInfo>) {
+    if let Some(song) = song {
+        let _ = writeln!(out, "[ti:{}]", song.title.trim());
+        let _ = writeln!(out, "[ar:{}]", song.artist.trim());
+    }
+}
+
+/// Lines that have something to show
+fn sung_lines(lines: &[TimedLine]) -> impl Iterator<Item = &TimedLine> {
+    lines.iter().filter(|line| !line.text().trim().is_empty())
+}
+
+/// Writes line-level LRC from timed lines in milliseconds
+pub fn to_lrc(lines: &[TimedLine], song: Option<&SongInfo>) -> String {
+    let mut out = String::new();
+    write_tags(&mut out, song);
+    for line in sung_lines(lines) {
+        let start = line.start().unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "[{}]{}",
+            format_timestamp(start),
+            line.text().trim_end()
+        );
+    }
+    out
+}
+
+/// Writes enhanced LRC, where every syllable is preceded by its `<mm:ss.xx>` start time and the
+/// line ends with the time the last syllable ends
+pub fn to_enhanced_lrc(lines: &[TimedLine], song: Option<&SongInfo>) -> String {
+    let mut out = String::new();
+    write_tags(&mut out, song);
+    for line in sung_lines(lines) {
+        let start = line.start().unwrap_or_default();
+        let _ = write!(out, "[{}]", format_timestamp(start));
+        for syllable in &line.syllables {
+            let _ = write!(
+                out,
+                "<{}>{}",
+                format_timestamp(syllable.start),
+                syllable.text
+            );
+        }
+        let end = line.end().unwrap_or_default();
+        let _ = writeln!(out, "<{}>", format_timestamp(end));
+    }
+    out
+}
+
+/// Parses an LRC timestamp, `mm:ss.xx`, `mm:ss.xxx` or `mm:ss`, into milliseconds
+pub fn parse_timestamp(s: &str) -> Option<u32> {
+    let (minutes, seconds) = s.trim().split_once(':')?;
+    let (seconds, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    let millis = match fraction.len() {
+        0 => 0,
+        // Hundredths or thousandths of a second
+        1..=3 => fraction.parse::<u32>().ok()? * 10u32.pow(3 - fraction.len() as u32),
+        _ => return None,
+    };
+    let minutes = minutes.parse::<u32>().ok()?;
+    let seconds = seconds.parse::<u32>().ok()?;
+    if seconds >= 60 {
+        return None;
+    }
+    minutes
+        .checked_mul(60_000)?
+        .checked_add(seconds * 1000 + millis)
+}
+
+/// A parsed LRC or enhanced LRC file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Lrc {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// Lines sorted by start time, in milliseconds. Lines without word timing are a single
+    /// syllable.
+    pub lines: Vec<TimedLine>,
+}
+
+/// A line as written in the file, before the ends of its syllables are known
+struct RawLine {
+    start: u32,
+    syllables: Vec<(u32, String)>,
+    end: Option<u32>,
+}
+
+impl Lrc {
+    /// Parses LRC text. Lines that are not lyrics or tags are skipped, like players do.
+    pub fn parse(text: &str) -> Self {
+        let mut lrc = Lrc::default();
+        let mut offset = 0i64;
+        let mut raw_lines = Vec::new();
+
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            while let Some(tag) = rest.strip_prefix('[') {
+                let Some((tag, tail)) = tag.split_once(']') else {
+                    break;
+                };
+                rest = tail;
+                if let Some(time) = parse_timestamp(tag) {
+                    times.push(time);
+                } else if let Some((key, value)) = tag.split_once(':') {
+                    let value = value.trim().to_string();
+                    match key.trim() {
+                        "ti" => lrc.title = Some(value),
+                        "ar" => lrc.artist = Some(value),
+                        "offset" => offset = value.parse().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+            }
+            let Some(&first) = times.first() else {
+                continue;
+            };
+
+            let (syllables, end) = parse_words(rest, first);
+            for time in times {
+                // Word times of a repeated line are relative to its first time
+                let shift = |t: u32| (t - first).saturating_add(time);
+                raw_lines.push(RawLine {
+                    start: time,
+                    syllables: syllables
+                        .iter()
+                        .map(|(t, s)| (shift(*t), s.clone()))
+                        .collect(),
+                    end: end.map(shift),
+                });
+            }
+        }
+
+        // A positive offset makes the lyrics come earlier
+        let adjust = |t: u32| (t as i64 - offset).clamp(0, u32::MAX as i64) as u32;
+        raw_lines.sort_by_key(|line| line.start);
+        let starts = raw_lines.iter().map(|line| line.start).collect::<Vec<_>>();
+        for (i, line) in raw_lines.iter().enumerate() {
+            // A line without text ends the one before it
+            let next_line = starts.get(i + 1).copied();
+            let syllables = line
+                .syllables
+                .iter()
+                .enumerate()
+                .map(|(j, (start, text))| {
+                    let end = match line.syllables.get(j + 1) {
+                        Some(next) => next.0,
+                        None => line.end.or(next_line).unwrap_or(*start),
+                    };
+                    TimedSyllable {
+                        text: text.clone(),
+                        start: adjust(*start),
+                        end: adjust(end.max(*start)),
+                    }
+                })
+                .collect();
+            lrc.lines.push(TimedLine { syllables });
+        }
+        lrc
+    }
+}
+
+/// Splits the text of a line at its `<mm:ss.xx>` word times, returning the syllables and the
+/// time the line ends, if the line ends with a word time
+fn parse_words(text: &str, start: u32) -> (Vec<(u32, String)>, Option<u32>) {
+    let mut syllables = Vec::new();
+    let mut time = start;
+    let mut end = None;
+    let mut rest = text;
+    loop {
+        let timestamp = rest
+            .find('<')
+            .and_then(|open| Some((open, open + rest[open..].find('>')?)))
+            .and_then(|(open, close)| {
+                Some((open, close, parse_timestamp(&rest[open + 1..close])?))
+            });
+        let (word, next) = match timestamp {
+            Some((open, close, next)) => {
+                let word = &rest[..open];
+                rest = &rest[close + 1..];
+                (word, Some(next))
+            }
+            None => (std::mem::take(&mut rest), None),
+        };
+        if !word.is_empty() {
+            syllables.push((time, word.to_string()));
+            end = None;
+        }
+        match next {
+            Some(next) => {
+                time = next.max(time);
+                end = Some(time);
+            }
+            None => break,
+        }
+    }
+    (syllables, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_timestamp, parse_timestamp, to_enhanced_lrc, Lrc};
+    use crate::test_util::sample;
+    use crate::timed::{TimedLine, TimedSyllable};
+    use crate::types::EmkFile;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "00:00.00");
+        assert_eq!(format_timestamp(61_234), "01:01.23");
+        assert_eq!(format_timestamp(59_996), "01:00.00");
+    }
+
+    #[test]
+    fn test_enhanced_lrc() {
+        let syllable = |text: &str, start, end| TimedSyllable {
+            text: text.to_string(),
+            start,
+            end,
+        };
+        let lines = [TimedLine {
+            syllables: vec![syllable("Jen", 1000, 1500), syllable("ny", 1500, 2000)],
+        }];
+        assert_eq!(
+            to_enhanced_lrc(&lines, None),
+            "[00:01.00]<00:01.00>Jen<00:01.50>ny<00:02.00>\n"
+        );
+    }
+
+    #[test]
+    fn test_lrc() {
+        let file = sample();
+        let lrc = file.to_lrc().unwrap();
+        let mut lines = lrc.lines();
+        assert_eq!(lines.next(), Some("[ti:8675309Jenny Jenny]"));
+        assert_eq!(lines.next(), Some("[ar:Tommy Tutone]"));
+        assert_eq!(lines.next(), Some("[00:00.20]Nick_Original"));
+        assert!(lrc.contains("]>>>Jenny, Jenny,\n"));
+
+        let enhanced = file.to_enhanced_lrc().unwrap();
+        assert_eq!(enhanced.lines().count(), lrc.lines().count());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("01:01.23"), Some(61_230));
+        assert_eq!(parse_timestamp("00:02.5"), Some(2_500));
+        assert_eq!(parse_timestamp("00:02.345"), Some(2_345));
+        assert_eq!(parse_timestamp("10:00"), Some(600_000));
+        assert_eq!(parse_timestamp("ar:x"), None);
+        assert_eq!(parse_timestamp("00:61.00"), None);
+    }
+
+    #[test]
+    fn test_parse_lrc() {
+        let lrc = Lrc::parse(
+            "[ti:Jenny]\n[ar:Tommy]\n[offset:100]\n\
+             [00:03.00]<00:03.00>Jen<00:03.50>ny<00:04.00>\n\
+             [00:01.00][00:05.00]Who can I turn to\n\
+             [00:06.00]\n\
+             not a lyric\n",
+        );
+        assert_eq!(lrc.title.as_deref(), Some("Jenny"));
+        assert_eq!(lrc.artist.as_deref(), Some("Tommy"));
+
+        let lines = lrc
+            .lines
+            .iter()
+            .map(|line| {
+                line.syllables
+                    .iter()
+                    .map(|s| (s.text.as_str(), s.start, s.end))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                vec![("Who can I turn to", 900, 2900)],
+                vec![("Jen", 2900, 3400), ("ny", 3400, 3900)],
+                vec![("Who can I turn to", 4900, 5900)],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_lrc_late_times() {
+        // The repeat of the line would end past the largest time a u32 holds
+        let lrc = Lrc::parse("[00:01.00][71582:47.00]<00:01.00>Jen<00:02.00>ny<00:03.00>\n");
+        let last = &lrc.lines[1].syllables;
+        assert_eq!(last[0].start, 4_294_967_000);
+        assert_eq!((last[1].start, last[1].end), (u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn test_parse_enhanced_lrc() {
+        let file = sample();
+        let lrc = Lrc::parse(&file.to_enhanced_lrc().unwrap());
+        assert_eq!(lrc.title.as_deref(), Some("8675309Jenny Jenny"));
+        let sung = file
+            .timed_lyrics_ms()
+            .unwrap()
+            .into_iter()
+            .filter(|line| !line.text().trim().is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(lrc.lines.len(), sung.len());
+        for (parsed, line) in lrc.lines.iter().zip(&sung) {
+            assert_eq!(parsed.syllables.len(), line.syllables.len());
+            assert_eq!(parsed.text(), line.text());
+            assert!(parsed.start().unwrap().abs_diff(line.start().unwrap()) <= 5);
+        }
+    }
+
+    #[test]
+    fn test_import_lrc() {
+        let file = sample();
+        let midi = file.get_data("MIDI_DATA").unwrap().data.to_bytes();
+        let lrc = "[ti:Jenny]\n[ar:Tommy]\n[00:01.00]Jenny\n\
+                   [00:03.00]<00:03.00>8675<00:04.00>309<00:05.00>\n";
+        let imported = EmkFile::from_midi_lrc_bytes("000002", midi, lrc).unwrap();
+
+        let song = imported.song_info().unwrap();
+        assert_eq!(
+            (song.code.as_str(), song.title.as_str()),
+            ("000002", "Jenny")
+        );
+        assert_eq!(imported.lyrics().unwrap().lines(), ["Jenny", "8675309"]);
+
+        let lines = imported.timed_lyrics_ms().unwrap();
+        // Cursor units are 1/24 of a quarter note, about 18ms at 140 BPM
+        let near = |ms: u32, expected: u32| ms.abs_diff(expected) <= 18;
+        assert!(near(lines[0].start().unwrap(), 1000));
+        assert_eq!(lines[0].syllables.len(), 5);
+        assert!(near(lines[1].syllables[1].start, 4000));
+        assert!(imported.to_bytes().is_ok());
+    }
+}
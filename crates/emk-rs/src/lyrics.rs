@@ -0,0 +1,284 @@
+use std::ops::Range;
+
+use crate::error::{EmkError, Result};
+
+/// Number of lines before the sung lyrics in an NCN lyric file
+const HEADER_LINES: usize = 4;
+/// Index of the header line holding the key of the song
+const KEY_LINE: usize = 2;
+
+/// Text encoding of a lyric file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LyricEncoding {
+    /// Windows-874, a superset of TIS-620, used by Extreme Karaoke and NCN for Thai songs
+    Windows874,
+    Utf8,
+}
+
+impl LyricEncoding {
+    /// Guesses the encoding of a lyric file.
+    ///
+    /// Thai text in TIS-620 is almost never valid UTF-8, so anything that decodes as UTF-8 with
+    /// non-ASCII characters is assumed to be UTF-8. Everything else, including plain ASCII, is
+    /// treated as Windows-874.
+    pub fn detect(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(s) if !s.is_ascii() => LyricEncoding::Utf8,
+            _ => LyricEncoding::Windows874,
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> String {
+        match self {
+            LyricEncoding::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            LyricEncoding::Windows874 => data.iter().map(|&b| decode_874(b)).collect(),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            LyricEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            LyricEncoding::Windows874 => text
+                .chars()
+                .map(|ch| {
+                    encode_874(ch).ok_or(EmkError::UnencodableChar {
+                        ch,
+                        encoding: "Windows-874",
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Like [`LyricEncoding::encode`], but writes `?` for the characters that cannot be encoded
+    pub fn encode_lossy(self, text: &str) -> Vec<u8> {
+        match self {
+            LyricEncoding::Utf8 => text.as_bytes().to_vec(),
+            LyricEncoding::Windows874 => text
+                .chars()
+                .map(|ch| encode_874(ch).unwrap_or(b'?'))
+                .collect(),
+        }
+    }
+}
+
+/// Maps a Windows-874 byte to its character, or U+FFFD for the unassigned bytes
+fn decode_874(b: u8) -> char {
+    let ch = match b {
+        0x00..=0x7F => b as u32,
+        0x80 => 0x20AC,
+        0x85 => 0x2026,
+        0x91 => 0x2018,
+        0x92 => 0x2019,
+        0x93 => 0x201C,
+        0x94 => 0x201D,
+        0x95 => 0x2022,
+        0x96 => 0x2013,
+        0x97 => 0x2014,
+        0xA0 => 0x00A0,
+        // The Thai block is laid out in the same order as in Unicode
+        0xA1..=0xDA | 0xDF..=0xFB => b as u32 - 0xA0 + 0x0E00,
+        _ => 0xFFFD,
+    };
+    char::from_u32(ch).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn encode_874(ch: char) -> Option<u8> {
+    let b = match ch as u32 {
+        c @ 0x00..=0x7F => c as u8,
+        0x20AC => 0x80,
+        0x2026 => 0x85,
+        0x2018 => 0x91,
+        0x2019 => 0x92,
+        0x201C => 0x93,
+        0x201D => 0x94,
+        0x2022 => 0x95,
+        0x2013 => 0x96,
+        0x2014 => 0x97,
+        0x00A0 => 0xA0,
+        c @ (0x0E01..=0x0E3A | 0x0E3F..=0x0E5B) => (c - 0x0E00 + 0xA0) as u8,
+        _ => return None,
+    };
+    Some(b)
+}
+
+/// Decoded contents of `LYRIC_DATA`, an NCN lyric file.
+///
+/// The first four lines hold the title, artist and key of the song, and are followed by the sung
+/// lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lyrics {
+    pub title: String,
+    pub artist: String,
+    pub key: String,
+    /// Fourth header line, usually empty
+    pub extra: String,
+    pub lines: Vec<String>,
+    /// Encoding the lyrics were read with, and will be written with
+    pub encoding: LyricEncoding,
+}
+
+impl Lyrics {
+    /// New lyrics, in Windows-874 if every character fits in it and UTF-8 otherwise
+    pub fn new(title: &str, artist: &str, lines: Vec<String>) -> Self {
+        let fits = [title, artist]
+            .into_iter()
+            .chain(lines.iter().map(String::as_str))
+            .flat_map(str::chars)
+            .all(|ch| encode_874(ch).is_some());
+        Self {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            key: String::new(),
+            extra: String::new(),
+            lines,
+            encoding: if fits {
+                LyricEncoding::Windows874
+            } else {
+                LyricEncoding::Utf8
+            },
+        }
+    }
+
+    /// Decodes a lyric file, detecting its encoding
+    pub fn decode(data: &[u8]) -> Self {
+        Self::decode_with(data, LyricEncoding::detect(data))
+    }
+
+    pub fn decode_with(data: &[u8], encoding: LyricEncoding) -> Self {
+        let text = encoding.decode(data);
+        let mut lines = text.lines().map(str::to_string);
+        let mut header = || lines.next().unwrap_or_default();
+        let (title, artist, key, extra) = (header(), header(), header(), header());
+        Self {
+            title,
+            artist,
+            key,
+            extra,
+            lines: lines.collect(),
+            encoding,
+        }
+    }
+
+    /// The sung lines, without the header
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Number of cursor cells taken up by the sung lines
+    pub fn cell_count(&self) -> usize {
+        self.lines.iter().map(|line| cells(line).count()).sum()
+    }
+
+    /// The whole file as text, with `\r\n` line endings
+    pub fn to_text(&self) -> String {
+        let header = [&self.title, &self.artist, &self.key, &self.extra];
+        let mut text = Vec::with_capacity(HEADER_LINES + self.lines.len());
+        text.extend(header.into_iter().map(String::as_str));
+        text.extend(self.lines.iter().map(String::as_str));
+        text.join("\r\n")
+    }
+
+    /// Encodes the lyrics back into a lyric file
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.encoding.encode(&self.to_text())
+    }
+
+    /// Replaces the key in the header of a lyric file, leaving every other byte as it is so that
+    /// line breaks and bytes the encoding cannot decode survive. Returns `None` if the file has no
+    /// key line.
+    pub fn replace_key(data: &[u8], key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(line) = header_line(data, KEY_LINE) else {
+            return Ok(None);
+        };
+        // Keep the whitespace around the key
+        let text = &data[line.clone()];
+        let start = text.len() - text.trim_ascii_start().len();
+        let end = start + text.trim_ascii().len();
+        let key = LyricEncoding::detect(data).encode(key)?;
+
+        let mut out = Vec::with_capacity(data.len() + key.len());
+        out.extend_from_slice(&data[..line.start + start]);
+        out.extend_from_slice(&key);
+        out.extend_from_slice(&data[line.start + end..]);
+        Ok(Some(out))
+    }
+}
+
+/// Byte range of a header line, without its line break, split the same way as [`str::lines`]
+fn header_line(data: &[u8], index: usize) -> Option<Range<usize>> {
+    let mut start = 0;
+    for _ in 0..index {
+        start += data[start..].iter().position(|&b| b == b'\n')? + 1;
+    }
+    if start == data.len() {
+        return None;
+    }
+    let end = data[start..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(data.len(), |i| start + i);
+    let end = end - usize::from(end > start && data[end - 1] == b'\r');
+    Some(start..end)
+}
+
+/// Whether a Thai character is an above/below vowel or tone mark, which is drawn over or under
+/// the previous character and shares its cursor cell
+pub fn is_thai_combining(ch: char) -> bool {
+    matches!(ch, '\u{0E31}' | '\u{0E34}'..='\u{0E3A}' | '\u{0E47}'..='\u{0E4E}')
+}
+
+/// Splits a line into cursor cells: a base character followed by the marks combining with it
+pub fn cells(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line;
+    std::iter::from_fn(move || {
+        let mut chars = rest.char_indices();
+        chars.next()?;
+        let end = chars
+            .find(|&(_, ch)| !is_thai_combining(ch))
+            .map_or(rest.len(), |(i, _)| i);
+        let (cell, tail) = rest.split_at(end);
+        rest = tail;
+        Some(cell)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LyricEncoding, Lyrics};
+    use crate::test_util::sample;
+
+    #[test]
+    fn test_decode_lyrics() {
+        let file = sample();
+        let lyrics = file.lyrics().unwrap();
+        assert_eq!(lyrics.title, "8675309[Jenny Jenny]");
+        assert_eq!(lyrics.artist, "Tommy Tutone ");
+        assert_eq!(lyrics.key, "F#m");
+        assert_eq!(lyrics.lines()[2], ">>>Jenny, Jenny, ");
+        assert_eq!(lyrics.encoding, LyricEncoding::Windows874);
+
+        let raw = file.get_data("LYRIC_DATA").unwrap().data.to_bytes();
+        assert_eq!(lyrics.to_bytes().unwrap(), raw);
+    }
+
+    #[test]
+    fn test_thai_round_trip() {
+        // "เพลง" in TIS-620
+        let data = b"Title\r\nArtist\r\nC\r\n\r\n\xE0\xBE\xC5\xA7";
+        let lyrics = Lyrics::decode(data);
+        assert_eq!(lyrics.encoding, LyricEncoding::Windows874);
+        assert_eq!(lyrics.lines(), ["เพลง"]);
+        assert_eq!(lyrics.to_bytes().unwrap(), data);
+
+        assert_eq!(
+            super::cells("เพลง").collect::<Vec<_>>(),
+            ["เ", "พ", "ล", "ง"]
+        );
+        assert_eq!(super::cells("ที่นี่").collect::<Vec<_>>(), ["ที่", "นี่"]);
+
+        let utf8 = Lyrics::decode("Title\r\nArtist\r\nC\r\n\r\nเพลง".as_bytes());
+        assert_eq!(utf8.encoding, LyricEncoding::Utf8);
+        assert_eq!(utf8.lines(), lyrics.lines());
+    }
+}
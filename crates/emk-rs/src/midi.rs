@@ -0,0 +1,855 @@
+use std::fmt;
+
+use crate::error::{EmkError, Result};
+use crate::key::Key;
+use crate::timebase::DEFAULT_TEMPO;
+
+/// A Standard MIDI File, such as `MIDI_DATA`.
+///
+/// Events are kept in file order with their delta times, so a parsed file is written back the
+/// way it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiSong {
+    /// 0 for a single track, 1 for tracks played together, 2 for independent patterns
+    pub format: u16,
+    /// Ticks per quarter note
+    pub ppq: u16,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Track {
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Ticks since the previous event of the track
+    pub delta: u32,
+    pub kind: EventKind,
+    /// Whether the status byte of a channel message was left out because it is the same as the
+    /// previous one. Files mix both forms, so this is kept to write them back byte for byte.
+    pub running_status: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// A channel message, on channel 0 to 15
+    Channel {
+        channel: u8,
+        message: ChannelMessage,
+    },
+    Meta(Meta),
+    /// A system exclusive message starting with `0xF0`, without the `0xF0`
+    SysEx(Vec<u8>),
+    /// An escape event starting with `0xF7`, holding raw bytes to send
+    Escape(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMessage {
+    NoteOff {
+        key: u8,
+        velocity: u8,
+    },
+    /// A note on with velocity 0 is a note off
+    NoteOn {
+        key: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        key: u8,
+        pressure: u8,
+    },
+    Controller {
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        program: u8,
+    },
+    ChannelPressure {
+        pressure: u8,
+    },
+    /// 14-bit value, centered on 0x2000
+    PitchBend {
+        value: u16,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Meta {
+    /// Microseconds per quarter note
+    Tempo(u32),
+    TimeSignature {
+        numerator: u8,
+        /// Power of two of the denominator, 2 for x/4
+        denominator: u8,
+        /// MIDI clocks per metronome click
+        clocks_per_click: u8,
+        /// Notated 32nd notes per quarter note
+        notated_32nds: u8,
+    },
+    KeySignature {
+        /// Sharps if positive, flats if negative
+        sharps: i8,
+        minor: bool,
+    },
+    /// Text in the encoding the file was written in
+    TrackName(Vec<u8>),
+    Text(Vec<u8>),
+    Lyric(Vec<u8>),
+    EndOfTrack,
+    /// Any other meta event, or a known one with an unexpected length
+    Other {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Meta {
+    pub const TEXT: u8 = 0x01;
+    pub const TRACK_NAME: u8 = 0x03;
+    pub const LYRIC: u8 = 0x05;
+    pub const END_OF_TRACK: u8 = 0x2F;
+    pub const TEMPO: u8 = 0x51;
+    pub const TIME_SIGNATURE: u8 = 0x58;
+    pub const KEY_SIGNATURE: u8 = 0x59;
+
+    fn parse(kind: u8, data: &[u8]) -> Self {
+        match (kind, data) {
+            (Self::TEXT, _) => Meta::Text(data.to_vec()),
+            (Self::TRACK_NAME, _) => Meta::TrackName(data.to_vec()),
+            (Self::LYRIC, _) => Meta::Lyric(data.to_vec()),
+            (Self::END_OF_TRACK, []) => Meta::EndOfTrack,
+            (Self::TEMPO, &[a, b, c]) => Meta::Tempo(u32::from_be_bytes([0, a, b, c])),
+            (Self::TIME_SIGNATURE, &[numerator, denominator, clocks_per_click, notated_32nds]) => {
+                Meta::TimeSignature {
+                    numerator,
+                    denominator,
+                    clocks_per_click,
+                    notated_32nds,
+                }
+            }
+            (Self::KEY_SIGNATURE, &[sharps, minor @ (0 | 1)]) => Meta::KeySignature {
+                sharps: sharps as i8,
+                minor: minor == 1,
+            },
+            _ => Meta::Other {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            Meta::Tempo(_) => Self::TEMPO,
+            Meta::TimeSignature { .. } => Self::TIME_SIGNATURE,
+            Meta::KeySignature { .. } => Self::KEY_SIGNATURE,
+            Meta::TrackName(_) => Self::TRACK_NAME,
+            Meta::Text(_) => Self::TEXT,
+            Meta::Lyric(_) => Self::LYRIC,
+            Meta::EndOfTrack => Self::END_OF_TRACK,
+            Meta::Other { kind, .. } => *kind,
+        }
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            Meta::Tempo(tempo) => tempo.to_be_bytes()[1..].to_vec(),
+            Meta::TimeSignature {
+                numerator,
+                denominator,
+                clocks_per_click,
+                notated_32nds,
+            } => vec![*numerator, *denominator, *clocks_per_click, *notated_32nds],
+            Meta::KeySignature { sharps, minor } => vec![*sharps as u8, *minor as u8],
+            Meta::TrackName(data) | Meta::Text(data) | Meta::Lyric(data) => data.clone(),
+            Meta::EndOfTrack => Vec::new(),
+            Meta::Other { data, .. } => data.clone(),
+        }
+    }
+}
+
+impl ChannelMessage {
+    /// Number of data bytes following a status byte
+    fn data_len(status: u8) -> usize {
+        match status & 0xF0 {
+            0xC0 | 0xD0 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Parses a channel message, or returns `None` for a system status
+    fn parse(status: u8, data: &[u8]) -> Option<Self> {
+        let (a, b) = (data[0], data.get(1).copied().unwrap_or(0));
+        Some(match status & 0xF0 {
+            0x80 => ChannelMessage::NoteOff {
+                key: a,
+                velocity: b,
+            },
+            0x90 => ChannelMessage::NoteOn {
+                key: a,
+                velocity: b,
+            },
+            0xA0 => ChannelMessage::PolyPressure {
+                key: a,
+                pressure: b,
+            },
+            0xB0 => ChannelMessage::Controller {
+                controller: a,
+                value: b,
+            },
+            0xC0 => ChannelMessage::ProgramChange { program: a },
+            0xD0 => ChannelMessage::ChannelPressure { pressure: a },
+            0xE0 => ChannelMessage::PitchBend {
+                value: (a as u16 & 0x7F) | (b as u16 & 0x7F) << 7,
+            },
+            _ => return None,
+        })
+    }
+
+    /// The high nibble of the status byte and the data bytes
+    fn to_bytes(self) -> (u8, [u8; 2], usize) {
+        match self {
+            ChannelMessage::NoteOff { key, velocity } => (0x80, [key, velocity], 2),
+            ChannelMessage::NoteOn { key, velocity } => (0x90, [key, velocity], 2),
+            ChannelMessage::PolyPressure { key, pressure } => (0xA0, [key, pressure], 2),
+            ChannelMessage::Controller { controller, value } => (0xB0, [controller, value], 2),
+            ChannelMessage::ProgramChange { program } => (0xC0, [program, 0], 1),
+            ChannelMessage::ChannelPressure { pressure } => (0xD0, [pressure, 0], 1),
+            ChannelMessage::PitchBend { value } => {
+                (0xE0, [(value & 0x7F) as u8, (value >> 7 & 0x7F) as u8], 2)
+            }
+        }
+    }
+}
+
+impl Track {
+    /// Events with their absolute time in ticks
+    pub fn timed_events(&self) -> impl Iterator<Item = (u32, &Event)> {
+        self.events.iter().scan(0u32, |tick, event| {
+            *tick = tick.saturating_add(event.delta);
+            Some((*tick, event))
+        })
+    }
+
+    /// Meta events with their absolute time in ticks
+    pub fn meta_events(&self) -> impl Iterator<Item = (u32, &Meta)> {
+        self.timed_events()
+            .filter_map(|(tick, event)| match &event.kind {
+                EventKind::Meta(meta) => Some((tick, meta)),
+                _ => None,
+            })
+    }
+
+    /// Name of the track, from its first track name event
+    pub fn name(&self) -> Option<&[u8]> {
+        self.meta_events().find_map(|(_, meta)| match meta {
+            Meta::TrackName(name) => Some(name.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Removes the events `keep` returns false for, moving their delta times to the next event so
+    /// the other events keep their times
+    pub fn retain_events(&mut self, mut keep: impl FnMut(&Event) -> bool) {
+        let mut carry = 0u32;
+        self.events.retain_mut(|event| {
+            if keep(event) {
+                event.delta = event.delta.saturating_add(carry);
+                carry = 0;
+                true
+            } else {
+                carry = carry.saturating_add(event.delta);
+                false
+            }
+        });
+    }
+
+    /// Builds a track from events at absolute times, which must be sorted, ending it with an
+    /// end of track event if it has none
+    pub fn from_timed_events(events: impl IntoIterator<Item = (u32, EventKind)>) -> Self {
+        let mut track = Track::default();
+        let mut last = 0;
+        for (tick, kind) in events {
+            let tick = tick.max(last);
+            track.events.push(Event {
+                delta: tick - last,
+                kind,
+                running_status: false,
+            });
+            last = tick;
+        }
+        if !matches!(
+            track.events.last(),
+            Some(Event {
+                kind: EventKind::Meta(Meta::EndOfTrack),
+                ..
+            })
+        ) {
+            track.events.push(Event {
+                delta: 0,
+                kind: EventKind::Meta(Meta::EndOfTrack),
+                running_status: false,
+            });
+        }
+        track
+    }
+}
+
+impl fmt::Debug for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Track")
+            .field("name", &self.name().map(String::from_utf8_lossy))
+            .field("events", &self.events.len())
+            .finish()
+    }
+}
+
+/// Channel of General MIDI percussion, channel 10 counting from 1
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+fn invalid_midi(offset: usize, reason: &'static str) -> EmkError {
+    EmkError::InvalidMidi { offset, reason }
+}
+
+/// Reads a MIDI variable-length quantity
+fn read_vlq(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = *data.get(*pos).ok_or(invalid_midi(*pos, "truncated"))?;
+        *pos += 1;
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_midi(*pos, "variable-length quantity too long"))
+}
+
+/// Writes a MIDI variable-length quantity
+pub(crate) fn write_vlq(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0u8; 5];
+    let mut len = 0;
+    let mut value = value;
+    loop {
+        bytes[len] = (value & 0x7F) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for (i, byte) in bytes[..len].iter().enumerate().rev() {
+        out.push(if i > 0 { byte | 0x80 } else { *byte });
+    }
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..pos.saturating_add(len))
+        .ok_or(invalid_midi(*pos, "truncated event"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+impl MidiSong {
+    /// Parses a Standard MIDI File. Chunks other than tracks are skipped.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.get(..4) != Some(b"MThd") || data.len() < 14 {
+            return Err(invalid_midi(0, "missing MThd header"));
+        }
+        let format = u16::from_be_bytes([data[8], data[9]]);
+        let ppq = u16::from_be_bytes([data[12], data[13]]);
+        if ppq & 0x8000 != 0 {
+            return Err(invalid_midi(12, "SMPTE time division is not supported"));
+        }
+        let header_len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+
+        let mut tracks = Vec::new();
+        let mut pos = 8usize.saturating_add(header_len);
+        while let Some(chunk) = data.get(pos..pos.saturating_add(8)) {
+            let len = u32::from_be_bytes(chunk[4..8].try_into().unwrap()) as usize;
+            let start = pos + 8;
+            let end = start.saturating_add(len).min(data.len());
+            if &chunk[..4] == b"MTrk" {
+                tracks.push(Self::parse_track(&data[..end], start)?);
+            }
+            pos = end;
+        }
+        Ok(Self {
+            format,
+            ppq,
+            tracks,
+        })
+    }
+
+    fn parse_track(data: &[u8], mut pos: usize) -> Result<Track> {
+        let mut track = Track::default();
+        let mut running_status = None;
+        while pos < data.len() {
+            let delta = read_vlq(data, &mut pos)?;
+            let status = *data.get(pos).ok_or(invalid_midi(pos, "truncated"))?;
+            let running = status & 0x80 == 0;
+            let kind = match status {
+                0xFF => {
+                    let kind = *data.get(pos + 1).ok_or(invalid_midi(pos, "truncated"))?;
+                    pos += 2;
+                    let len = read_vlq(data, &mut pos)? as usize;
+                    EventKind::Meta(Meta::parse(kind, read_bytes(data, &mut pos, len)?))
+                }
+                0xF0 | 0xF7 => {
+                    pos += 1;
+                    let len = read_vlq(data, &mut pos)? as usize;
+                    let bytes = read_bytes(data, &mut pos, len)?.to_vec();
+                    if status == 0xF0 {
+                        EventKind::SysEx(bytes)
+                    } else {
+                        EventKind::Escape(bytes)
+                    }
+                }
+                0x00..=0xEF => {
+                    let status = if status & 0x80 != 0 {
+                        pos += 1;
+                        running_status = Some(status);
+                        status
+                    } else {
+                        running_status.ok_or(invalid_midi(pos, "data byte without status"))?
+                    };
+                    let start = pos;
+                    let bytes = read_bytes(data, &mut pos, ChannelMessage::data_len(status))?;
+                    EventKind::Channel {
+                        channel: status & 0x0F,
+                        message: ChannelMessage::parse(status, bytes)
+                            .ok_or(invalid_midi(start, "unsupported status"))?,
+                    }
+                }
+                // System common and real-time messages do not belong in a MIDI file
+                _ => return Err(invalid_midi(pos, "unsupported system message")),
+            };
+            let end = matches!(kind, EventKind::Meta(Meta::EndOfTrack));
+            track.events.push(Event {
+                delta,
+                kind,
+                running_status: running,
+            });
+            // Anything after the end of the track is padding
+            if end {
+                break;
+            }
+        }
+        Ok(track)
+    }
+
+    /// Serializes the song. Running status is used where it was in the parsed file and the status
+    /// is still the same.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(&self.format.to_be_bytes());
+        out.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.ppq.to_be_bytes());
+
+        for track in &self.tracks {
+            let mut data = Vec::new();
+            let mut running_status = None;
+            for event in &track.events {
+                write_vlq(&mut data, event.delta);
+                match &event.kind {
+                    EventKind::Channel { channel, message } => {
+                        let (status, bytes, len) = message.to_bytes();
+                        let status = status | (channel & 0x0F);
+                        if !event.running_status || running_status != Some(status) {
+                            data.push(status);
+                            running_status = Some(status);
+                        }
+                        data.extend_from_slice(&bytes[..len]);
+                    }
+                    EventKind::Meta(meta) => {
+                        data.extend_from_slice(&[0xFF, meta.kind()]);
+                        let body = meta.data();
+                        write_vlq(&mut data, body.len() as u32);
+                        data.extend_from_slice(&body);
+                    }
+                    EventKind::SysEx(bytes) | EventKind::Escape(bytes) => {
+                        let status = match event.kind {
+                            EventKind::SysEx(_) => 0xF0,
+                            _ => 0xF7,
+                        };
+                        data.push(status);
+                        write_vlq(&mut data, bytes.len() as u32);
+                        data.extend_from_slice(bytes);
+                    }
+                }
+            }
+            out.extend_from_slice(b"MTrk");
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+
+    /// Shifts every note by `semitones`, except on the percussion channel, and moves the key
+    /// signatures along. Notes that would leave the MIDI range are moved by whole octaves
+    /// to stay in it.
+    pub fn transpose(&mut self, semitones: i32) {
+        let shift = |key: &mut u8| {
+            let mut shifted = *key as i32 + semitones;
+            while shifted > 127 {
+                shifted -= 12;
+            }
+            while shifted < 0 {
+                shifted += 12;
+            }
+            *key = shifted as u8;
+        };
+        let events = self.tracks.iter_mut().flat_map(|t| t.events.iter_mut());
+        for event in events {
+            match &mut event.kind {
+                EventKind::Channel {
+                    channel,
+                    message:
+                        ChannelMessage::NoteOff { key, .. }
+                        | ChannelMessage::NoteOn { key, .. }
+                        | ChannelMessage::PolyPressure { key, .. },
+                } if *channel != PERCUSSION_CHANNEL => shift(key),
+                EventKind::Meta(Meta::KeySignature { sharps, minor }) => {
+                    *sharps = Key::from_signature(*sharps, *minor)
+                        .transpose(semitones)
+                        .signature();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Plays the song `factor` times as fast by scaling its tempo events. A song without tempo
+    /// events gets one for the scaled default tempo at the start of its first track.
+    ///
+    /// Tick positions do not change, so anything timed in ticks stays in sync.
+    pub fn scale_tempo(&mut self, factor: f64) -> Result<()> {
+        if !(factor.is_finite() && factor > 0.0) {
+            return Err(EmkError::InvalidConversion {
+                value: factor.to_string(),
+                target: "tempo factor",
+            });
+        }
+        // Tempos are stored in 24 bits
+        let scale =
+            |tempo: u32| (tempo as f64 / factor).round().clamp(1.0, 0xFF_FFFF as f64) as u32;
+        if self.tempo_changes().is_empty() {
+            if let Some(track) = self.tracks.first_mut() {
+                track.events.insert(
+                    0,
+                    Event {
+                        delta: 0,
+                        kind: EventKind::Meta(Meta::Tempo(scale(DEFAULT_TEMPO))),
+                        running_status: false,
+                    },
+                );
+            }
+            return Ok(());
+        }
+        for event in self.tracks.iter_mut().flat_map(|t| t.events.iter_mut()) {
+            if let EventKind::Meta(Meta::Tempo(tempo)) = &mut event.kind {
+                *tempo = scale(*tempo);
+            }
+        }
+        Ok(())
+    }
+
+    /// Scales the velocity of every note played on `channel`, from 0 to 15, so a volume of 0
+    /// mutes it and 0.5 halves it. Notes that are still heard keep a velocity of at least 1.
+    pub fn set_channel_volume(&mut self, channel: u8, volume: f64) -> Result<()> {
+        check_channel(channel)?;
+        if !(volume.is_finite() && volume >= 0.0) {
+            return Err(EmkError::InvalidConversion {
+                value: volume.to_string(),
+                target: "channel volume",
+            });
+        }
+        let events = self.tracks.iter_mut().flat_map(|t| t.events.iter_mut());
+        for event in events {
+            if let EventKind::Channel {
+                channel: c,
+                message: ChannelMessage::NoteOn { velocity, .. },
+            } = &mut event.kind
+            {
+                // A velocity of 0 is a note off, which must stay one
+                if *c == channel && *velocity > 0 {
+                    let scaled = (*velocity as f64 * volume).round().min(127.0) as u8;
+                    *velocity = if volume > 0.0 { scaled.max(1) } else { 0 };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Silences every channel but `channel`, from 0 to 15
+    pub fn solo_channel(&mut self, channel: u8) -> Result<()> {
+        check_channel(channel)?;
+        for other in (0..16).filter(|&c| c != channel) {
+            self.set_channel_volume(other, 0.0)?;
+        }
+        Ok(())
+    }
+
+    /// `(tick, microseconds per quarter note)` of the tempo events of all tracks
+    pub fn tempo_changes(&self) -> Vec<(u32, u32)> {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.meta_events())
+            .filter_map(|(tick, meta)| match meta {
+                Meta::Tempo(tempo) => Some((tick, *tempo)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn check_channel(channel: u8) -> Result<()> {
+    if channel > 15 {
+        return Err(EmkError::InvalidConversion {
+            value: channel.to_string(),
+            target: "MIDI channel",
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_vlq, ChannelMessage, EventKind, Meta, MidiSong, Track, PERCUSSION_CHANNEL};
+    use crate::test_util::sample;
+    use crate::types::EmkFile;
+
+    #[test]
+    fn test_write_vlq() {
+        for (value, bytes) in [
+            (0, &[0x00][..]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x81, 0x00]),
+            (0x0FFF_FFFF, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut out = Vec::new();
+            write_vlq(&mut out, value);
+            assert_eq!(out, bytes);
+        }
+    }
+
+    #[test]
+    fn test_midi_round_trip() {
+        let file = sample();
+        let raw = file.get_data("MIDI_DATA").unwrap().data.to_bytes();
+        let song = file.midi().unwrap();
+        assert_eq!(song.format, 1);
+        assert_eq!(song.ppq, 96);
+        assert_eq!(song.tracks.len(), 12);
+        assert_eq!(song.tempo_changes()[0], (0, 428_571));
+        assert_eq!(song.to_bytes(), raw);
+    }
+
+    #[test]
+    fn test_events() {
+        let track = Track::from_timed_events([
+            (0, EventKind::Meta(Meta::TrackName(b"Piano".to_vec()))),
+            (
+                0,
+                EventKind::Meta(Meta::KeySignature {
+                    sharps: -3,
+                    minor: true,
+                }),
+            ),
+            (
+                96,
+                EventKind::Channel {
+                    channel: 2,
+                    message: ChannelMessage::NoteOn {
+                        key: 60,
+                        velocity: 100,
+                    },
+                },
+            ),
+            (
+                192,
+                EventKind::Channel {
+                    channel: 2,
+                    message: ChannelMessage::NoteOn {
+                        key: 60,
+                        velocity: 0,
+                    },
+                },
+            ),
+            (192, EventKind::SysEx(vec![0x7E, 0x7F, 0x09, 0x01, 0xF7])),
+            (
+                200,
+                EventKind::Channel {
+                    channel: 2,
+                    message: ChannelMessage::PitchBend { value: 0x2000 },
+                },
+            ),
+        ]);
+        assert_eq!(track.name(), Some(&b"Piano"[..]));
+        let song = MidiSong {
+            format: 0,
+            ppq: 96,
+            tracks: vec![track],
+        };
+        let bytes = song.to_bytes();
+        let notes = [0x60, 0x92, 60, 100, 0x60, 0x92, 60, 0];
+        assert!(bytes.windows(notes.len()).any(|w| w == notes));
+        assert_eq!(MidiSong::parse(&bytes).unwrap(), song);
+
+        // Running status is only used where the file used it
+        let mut song = song;
+        song.tracks[0].events[3].running_status = true;
+        let bytes = song.to_bytes();
+        let notes = [0x60, 0x92, 60, 100, 0x60, 60, 0];
+        assert!(bytes.windows(notes.len()).any(|w| w == notes));
+        assert_eq!(MidiSong::parse(&bytes).unwrap(), song);
+    }
+
+    #[test]
+    fn test_system_messages() {
+        let song = |events: &[u8]| {
+            let mut data = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+            data.extend_from_slice(&(events.len() as u32 + 4).to_be_bytes());
+            data.extend_from_slice(events);
+            data.extend_from_slice(b"\0\xFF\x2F\0");
+            MidiSong::parse(&data)
+        };
+        assert!(song(b"\0\xE1\x00\x40").is_ok());
+        for status in (0xF1..=0xF6).chain(0xF8..=0xFE) {
+            let err = song(&[0, status, 0, 0]).unwrap_err();
+            assert!(matches!(
+                err,
+                crate::EmkError::InvalidMidi { offset: 23, .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut file = sample();
+        let original = file.midi().unwrap();
+        file.transpose(2).unwrap();
+        let song = file.midi().unwrap();
+        assert_eq!(file.song_info().unwrap().key, "G#m");
+        assert_eq!(file.lyrics().unwrap().key.trim(), "G#m");
+
+        let notes = |song: &MidiSong, drums: bool| {
+            song.tracks
+                .iter()
+                .flat_map(|t| &t.events)
+                .filter_map(|e| match e.kind {
+                    EventKind::Channel {
+                        channel,
+                        message: ChannelMessage::NoteOn { key, .. },
+                    } if (channel == PERCUSSION_CHANNEL) == drums => Some(key),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(notes(&song, true), notes(&original, true));
+        let shifted = notes(&original, false)
+            .into_iter()
+            .map(|key| if key > 125 { key - 10 } else { key + 2 })
+            .collect::<Vec<_>>();
+        assert_eq!(notes(&song, false), shifted);
+        // C major moves to D major
+        let signatures = song.tracks[0]
+            .meta_events()
+            .filter_map(|(_, meta)| match meta {
+                Meta::KeySignature { sharps, minor } => Some((*sharps, *minor)),
+                _ => None,
+            });
+        assert_eq!(signatures.collect::<Vec<_>>(), [(2, false)]);
+
+        file.transpose(-2).unwrap();
+        assert_eq!(file.midi().unwrap(), original);
+        assert_eq!(file.song_info().unwrap().key, "F#m");
+    }
+
+    #[test]
+    fn test_scale_tempo() {
+        let mut file = sample();
+        let lyrics = file.timed_lyrics_ms().unwrap();
+        file.scale_tempo(0.5).unwrap();
+        assert_eq!(file.midi().unwrap().tempo_changes()[0], (0, 857_142));
+        assert_eq!(file.song_info().unwrap().tempo, 70);
+
+        // The cursor is untouched and the lyrics take twice as long
+        let scaled = file.timed_lyrics_ms().unwrap();
+        let first = |lines: &[crate::timed::TimedLine]| lines[0].start().unwrap() as i64;
+        assert!((first(&scaled) - 2 * first(&lyrics)).abs() <= 1);
+        let data = file.to_bytes().unwrap();
+        let reread = EmkFile::from_bytes(&data).unwrap();
+        assert_eq!(reread.song_info().unwrap().tempo, 70);
+        assert_eq!(reread.cursor().unwrap(), file.cursor().unwrap());
+
+        assert!(file.scale_tempo(0.0).is_err());
+        assert!(file.scale_tempo(f64::NAN).is_err());
+
+        let mut song = MidiSong {
+            format: 0,
+            ppq: 96,
+            tracks: vec![Track::from_timed_events([])],
+        };
+        song.scale_tempo(2.0).unwrap();
+        assert_eq!(song.tempo_changes(), [(0, 250_000)]);
+    }
+
+    #[test]
+    fn test_channel_volume() {
+        let mut file = sample();
+        // The guide melody is on channel 9, the clarinet
+        assert_eq!(file.vocal_channel().unwrap(), 8);
+
+        let velocities = |file: &EmkFile, channel: u8| {
+            file.midi()
+                .unwrap()
+                .tracks
+                .iter()
+                .flat_map(|t| &t.events)
+                .filter_map(|e| match e.kind {
+                    EventKind::Channel {
+                        channel: c,
+                        message: ChannelMessage::NoteOn { velocity, .. },
+                    } if c == channel => Some(velocity),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let original = velocities(&file, 8);
+        assert!(original.iter().any(|&v| v > 0));
+
+        file.set_vocal_volume(0.5).unwrap();
+        let halved = velocities(&file, 8);
+        assert!(halved
+            .iter()
+            .zip(&original)
+            .all(|(&h, &o)| (o == 0) == (h == 0) && h <= o));
+
+        file.mute_vocals().unwrap();
+        assert!(velocities(&file, 8).iter().all(|&v| v == 0));
+        assert!(velocities(&file, 9).iter().any(|&v| v > 0));
+
+        let mut file = sample();
+        file.solo_vocals().unwrap();
+        assert_eq!(velocities(&file, 8), original);
+        assert!(velocities(&file, 9).iter().all(|&v| v == 0));
+        let data = file.to_bytes().unwrap();
+        let reread = EmkFile::from_bytes(&data).unwrap();
+        assert_eq!(reread.midi().unwrap(), file.midi().unwrap());
+
+        assert!(file.set_channel_volume(0, -1.0).is_err());
+        // Channels past 15 do not exist and must not mute anything
+        let song = file.midi().unwrap();
+        assert!(file.set_channel_volume(16, 0.0).is_err());
+        assert!(file.mute_channel(16).is_err());
+        assert!(file.solo_channel(200).is_err());
+        assert_eq!(file.midi().unwrap(), song);
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{EmkError, Result};
+use crate::lyrics::Lyrics;
+use crate::timebase::TimeBase;
+use crate::types::{RawKv, SongInfo};
+
+/// Vocal channel of a song whose vocal channel is not known, the one Extreme Karaoke files
+/// usually have
+const DEFAULT_VOCAL_CHANNEL: u8 = 9;
+
+/// `LYRIC_TITLE` holds the first sung lines, up to about this many characters
+const LYRIC_TITLE_LEN: usize = 80;
+
+/// Paths of the files of a song in an NCN Karaoke library: `Song/<code>.mid`,
+/// `Lyrics/<code>.lyr` and `Cursor/<code>.cur`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NcnPaths {
+    pub midi: PathBuf,
+    pub lyrics: PathBuf,
+    pub cursor: PathBuf,
+}
+
+impl NcnPaths {
+    /// Fails if `code` is not a plain file name, so a hostile `SONG_INFO` cannot point the files
+    /// outside of `dir`
+    pub fn new(dir: &Path, code: &str) -> Result<Self> {
+        let plain = code
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || matches!(ch, '-' | '_' | '.' | ' '));
+        if !plain || code.trim().is_empty() || code.contains("..") {
+            return Err(EmkError::InvalidField {
+                tag: "SONG_INFO".to_string(),
+                field: "CODE".to_string(),
+                value: code.to_string(),
+            });
+        }
+        Ok(Self {
+            midi: dir.join("Song").join(format!("{code}.mid")),
+            lyrics: dir.join("Lyrics").join(format!("{code}.lyr")),
+            cursor: dir.join("Cursor").join(format!("{code}.cur")),
+        })
+    }
+}
+
+/// Derives the `SONG_INFO` of a song from its lyric file header and MIDI file
+pub fn song_info(code: &str, lyrics: &Lyrics, time_base: &TimeBase) -> SongInfo {
+    let thai = lyrics
+        .to_text()
+        .chars()
+        .any(|ch| matches!(ch, '\u{0E00}'..='\u{0E7F}'));
+
+    let mut lyric_title = String::new();
+    for line in lyrics.lines() {
+        if lyric_title.chars().count() >= LYRIC_TITLE_LEN {
+            break;
+        }
+        lyric_title.push(' ');
+        lyric_title.push_str(line.trim());
+    }
+
+    let tempo = time_base.tempos()[0].micros_per_quarter.max(1);
+    SongInfo {
+        code: code.to_string(),
+        song_type: "MIDI".to_string(),
+        subtitle_type: "EMK".to_string(),
+        title: lyrics.title.trim().replace(['[', ']'], ""),
+        key: lyrics.key.trim().to_string(),
+        artist: lyrics.artist.trim().to_string(),
+        language: if thai { "THAI" } else { "ENGLISH" }.to_string(),
+        vocal_channel: DEFAULT_VOCAL_CHANNEL,
+        file_name: format!("{code}.mid"),
+        lyric_title,
+        start_time: 0,
+        stop_time: 0,
+        tempo: (60_000_000.0 / tempo as f64).round() as u32,
+        raw: RawKv::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NcnPaths;
+    use crate::test_util::sample;
+    use crate::types::{EmkFile, TagData};
+    use crate::EmkError;
+
+    #[test]
+    fn test_ncn_round_trip() {
+        let file = sample();
+        let dir = std::env::temp_dir().join(format!("emk-rs-ncn-{}", std::process::id()));
+        let paths = file.export_ncn(&dir).unwrap();
+        assert_eq!(paths, NcnPaths::new(&dir, "000001").unwrap());
+        assert!(paths.lyrics.ends_with("Lyrics/000001.lyr"));
+
+        let imported = EmkFile::from_ncn(&paths.midi, &paths.lyrics, &paths.cursor).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for tag in ["MIDI_DATA", "LYRIC_DATA", "CURSOR_DATA"] {
+            let bytes = |file: &EmkFile| file.get_data(tag).unwrap().data.to_bytes();
+            assert_eq!(bytes(&imported), bytes(&file));
+        }
+        let (song, original) = (imported.song_info().unwrap(), file.song_info().unwrap());
+        assert_eq!(song.code, original.code);
+        assert_eq!(song.title, original.title);
+        assert_eq!(song.artist, original.artist);
+        assert_eq!(song.key, original.key);
+        assert_eq!(song.file_name, original.file_name);
+        assert_eq!(song.lyric_title, original.lyric_title);
+        assert_eq!(song.tempo, original.tempo);
+
+        // The new file can be written and read back
+        let data = imported.to_bytes().unwrap();
+        let reread = EmkFile::from_bytes(&data).unwrap();
+        assert!(reread.verify().is_ok());
+        assert_eq!(reread.song_info().unwrap().title, original.title);
+    }
+
+    #[test]
+    fn test_ncn_path_traversal() {
+        let dir = std::env::temp_dir().join(format!("emk-rs-ncn-evil-{}", std::process::id()));
+        for code in ["../../x", "..", "/etc/x", "C:x", "a\\b", "a\0b", "", " "] {
+            assert!(NcnPaths::new(&dir, code).is_err(), "{code:?}");
+        }
+        assert!(NcnPaths::new(&dir, "000001 v2.1").is_ok());
+
+        let mut file = sample();
+        if let TagData::SongInfo(s) = &mut file.get_data_mut("SONG_INFO").unwrap().data {
+            s.code = "../../x".to_string();
+        }
+        let err = file.export_ncn(&dir).unwrap_err();
+        assert!(matches!(
+            err,
+            EmkError::InvalidField { ref field, .. } if field == "CODE"
+        ));
+        assert!(!dir.exists());
+    }
+}
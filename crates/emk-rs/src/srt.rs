@@ -0,0 +1,55 @@
+use std::fmt::Write;
+
+use crate::timed::TimedLine;
+
+/// Formats milliseconds as an SRT timestamp, `hh:mm:ss,mmm`
+pub fn format_timestamp(ms: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// Writes SubRip subtitles from timed lines in milliseconds, with a cue for every sung line
+pub fn to_srt(lines: &[TimedLine]) -> String {
+    let mut out = String::new();
+    let cues = lines
+        .iter()
+        .filter(|line| !line.text().trim().is_empty())
+        .filter_map(|line| Some((line.start()?, line.end()?, line.text())));
+    for (i, (start, end, text)) in cues.enumerate() {
+        let _ = writeln!(out, "{}", i + 1);
+        let _ = writeln!(
+            out,
+            "{} --> {}",
+            format_timestamp(start),
+            format_timestamp(end)
+        );
+        let _ = writeln!(out, "{}", text.trim());
+        let _ = writeln!(out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_timestamp;
+    use crate::test_util::sample;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "00:00:00,000");
+        assert_eq!(format_timestamp(3_723_456), "01:02:03,456");
+    }
+
+    #[test]
+    fn test_srt() {
+        let file = sample();
+        let srt = file.to_srt().unwrap();
+        assert!(srt.starts_with("1\n00:00:00,196 --> "));
+        assert!(srt.contains("\n>>>Jenny, Jenny,\n\n"));
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{self, Read, Seek, SeekFrom, Take};
+
+use flate2::read::ZlibDecoder;
+
+use crate::error::{EmkError, Result};
+use crate::types::{read_entries, SongInfo, TagData, TagEntry};
+use crate::util::{xor_in_place_at, EMK_MAGIC, MAGIC_BYTES};
+
+/// Inflated payload of a single tag, read straight from the source
+pub type TagStream<'a, R> = ZlibDecoder<XorReader<'a, Take<&'a mut R>>>;
+
+/// Reads an EMK archive from any seekable source without loading it into memory.
+///
+/// Only the file magic, the header pointers and the tag table are read and decrypted up front.
+/// Tag payloads are decrypted and inflated while they are read.
+pub struct EmkStreamReader<R> {
+    inner: R,
+    key: Vec<u8>,
+    entries: Vec<TagEntry>,
+}
+
+impl<R: Read + Seek> EmkStreamReader<R> {
+    pub fn new(mut inner: R, key: &[u8]) -> Result<Self> {
+        if key.is_empty() {
+            return Err(EmkError::InvalidKey);
+        }
+        let len = inner.seek(SeekFrom::End(0))?;
+
+        let magic = read_at(&mut inner, key, len, 0, MAGIC_BYTES.len())?;
+        if magic != MAGIC_BYTES {
+            return Err(EmkError::InvalidKey);
+        }
+
+        let pointers = read_at(&mut inner, key, len, 0x22, 16)?;
+        let header_pos = u64::from_le_bytes(pointers[..8].try_into().unwrap());
+        let header_end = u64::from_le_bytes(pointers[8..].try_into().unwrap());
+        if header_end < header_pos || header_end > len {
+            return Err(EmkError::InvalidRange {
+                tag: None,
+                begin: header_pos as usize,
+                end: header_end as usize,
+            });
+        }
+
+        let table = read_at(
+            &mut inner,
+            key,
+            len,
+            header_pos,
+            (header_end - header_pos) as usize,
+        )?;
+        let entries = read_entries(&table, header_pos as usize)?;
+
+        Ok(Self {
+            inner,
+            key: key.to_vec(),
+            entries,
+        })
+    }
+
+    pub fn with_default_key(inner: R) -> Result<Self> {
+        Self::new(inner, EMK_MAGIC.to_be_bytes().as_ref())
+    }
+
+    /// Entries of the tag table, in the order they appear in the file
+    pub fn entries(&self) -> &[TagEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, tag: &str) -> Option<&TagEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// Opens the payload of a tag as a stream of inflated data
+    pub fn open_tag(&mut self, tag: &str) -> Result<TagStream<'_, R>> {
+        let entry = self
+            .entry(tag)
+            .ok_or_else(|| EmkError::MissingTag(tag.to_string()))?;
+        let (begin, end) = (entry.data_begin, entry.data_end);
+        if end < begin {
+            return Err(EmkError::InvalidRange {
+                tag: Some(tag.to_string()),
+                begin: begin as usize,
+                end: end as usize,
+            });
+        }
+
+        self.inner.seek(SeekFrom::Start(begin))?;
+        let compressed = XorReader {
+            inner: (&mut self.inner).take(end - begin),
+            key: &self.key,
+            pos: begin,
+        };
+        Ok(ZlibDecoder::new(compressed))
+    }
+
+    /// Inflates the data of a single tag
+    pub fn read_tag_data(&mut self, tag: &str) -> Result<Vec<u8>> {
+        let offset = self.entry(tag).map_or(0, |entry| entry.data_begin as usize);
+        let mut buf = Vec::new();
+        self.open_tag(tag)?
+            .read_to_end(&mut buf)
+            .map_err(|source| EmkError::Decompress {
+                tag: tag.to_string(),
+                offset,
+                source,
+            })?;
+        Ok(buf)
+    }
+
+    /// Inflates and parses a single tag
+    pub fn read_tag(&mut self, tag: &str) -> Result<TagData> {
+        let data = self.read_tag_data(tag)?;
+        TagData::from_buf_with_tag(tag, data)
+    }
+
+    /// Reads the song info without inflating any other tag
+    pub fn song_info(&mut self) -> Result<SongInfo> {
+        let data = self.read_tag_data("SONG_INFO")?;
+        SongInfo::from_kv_bytes(&data)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Decrypts a stream that starts at `pos` in the file
+pub struct XorReader<'k, R> {
+    inner: R,
+    key: &'k [u8],
+    pos: u64,
+}
+
+impl<R: Read> Read for XorReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        xor_in_place_at(&mut buf[..n], self.key, self.pos);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads and decrypts `n` bytes at `offset`, checking the range against the source length first
+fn read_at<R: Read + Seek>(
+    inner: &mut R,
+    key: &[u8],
+    len: u64,
+    offset: u64,
+    n: usize,
+) -> Result<Vec<u8>> {
+    if offset.saturating_add(n as u64) > len {
+        return Err(EmkError::Truncated {
+            tag: None,
+            offset: len as usize,
+        });
+    }
+    inner.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; n];
+    inner.read_exact(&mut buf)?;
+    xor_in_place_at(&mut buf, key, offset);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::EmkStreamReader;
+    use crate::test_util::TEST_DATA;
+    use crate::types::EmkReader;
+
+    #[test]
+    fn test_stream_matches_reader() {
+        let reader = EmkReader::decrypt_default_key(TEST_DATA).unwrap();
+        let mut stream = EmkStreamReader::with_default_key(Cursor::new(TEST_DATA)).unwrap();
+        assert_eq!(stream.entries().len(), reader.entries().len());
+
+        for entry in reader.entries() {
+            let expected = reader.inflate(entry).unwrap();
+            let mut buf = Vec::new();
+            stream
+                .open_tag(&entry.tag)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            assert_eq!(buf, expected, "{}", entry.tag);
+        }
+        assert_eq!(stream.song_info().unwrap().code, "000001");
+    }
+
+    #[test]
+    fn test_stream_wrong_key() {
+        let err = EmkStreamReader::new(Cursor::new(TEST_DATA), &[0x12, 0x34])
+            .err()
+            .unwrap();
+        assert!(err.is_key_error());
+    }
+}
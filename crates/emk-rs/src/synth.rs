@@ -0,0 +1,1122 @@
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::path::Path;
+
+use crate::error::{EmkError, Result};
+use crate::midi::{ChannelMessage, EventKind, MidiSong, PERCUSSION_CHANNEL};
+use crate::timebase::TimeBase;
+
+/// Generators of a SoundFont zone, by their index in the SF2 specification
+mod generator {
+    pub const START_OFFSET: u16 = 0;
+    pub const END_OFFSET: u16 = 1;
+    pub const LOOP_START_OFFSET: u16 = 2;
+    pub const LOOP_END_OFFSET: u16 = 3;
+    pub const START_COARSE_OFFSET: u16 = 4;
+    pub const END_COARSE_OFFSET: u16 = 12;
+    pub const CHORUS_SEND: u16 = 15;
+    pub const REVERB_SEND: u16 = 16;
+    pub const PAN: u16 = 17;
+    pub const DELAY_VOL_ENV: u16 = 33;
+    pub const ATTACK_VOL_ENV: u16 = 34;
+    pub const HOLD_VOL_ENV: u16 = 35;
+    pub const DECAY_VOL_ENV: u16 = 36;
+    pub const SUSTAIN_VOL_ENV: u16 = 37;
+    pub const RELEASE_VOL_ENV: u16 = 38;
+    pub const INSTRUMENT: u16 = 41;
+    pub const KEY_RANGE: u16 = 43;
+    pub const VEL_RANGE: u16 = 44;
+    pub const LOOP_START_COARSE_OFFSET: u16 = 45;
+    pub const INITIAL_ATTENUATION: u16 = 48;
+    pub const LOOP_END_COARSE_OFFSET: u16 = 50;
+    pub const COARSE_TUNE: u16 = 51;
+    pub const FINE_TUNE: u16 = 52;
+    pub const SAMPLE_ID: u16 = 53;
+    pub const SAMPLE_MODES: u16 = 54;
+    pub const SCALE_TUNING: u16 = 56;
+    pub const EXCLUSIVE_CLASS: u16 = 57;
+    pub const OVERRIDING_ROOT_KEY: u16 = 58;
+    pub const COUNT: usize = 61;
+}
+
+/// Bank of the percussion presets of a General MIDI SoundFont
+const PERCUSSION_BANK: u16 = 128;
+
+/// Voices playing at once, the oldest is cut off beyond this
+const MAX_VOICES: usize = 256;
+
+/// How long the last notes may ring after the last event of a song, in milliseconds
+const MAX_TAIL: u32 = 3000;
+
+fn invalid_sf2(offset: usize, reason: &'static str) -> EmkError {
+    EmkError::InvalidSoundFont { offset, reason }
+}
+
+/// A chunk of a RIFF file
+struct Chunk<'a> {
+    id: [u8; 4],
+    offset: usize,
+    data: &'a [u8],
+}
+
+/// Splits the body of a RIFF list into its chunks. `base` is the offset of `data` in the file.
+fn chunks(data: &[u8], base: usize) -> Result<Vec<Chunk<'_>>> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = data[pos..pos + 4].try_into().unwrap();
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let begin = pos + 8;
+        let end = begin
+            .checked_add(len)
+            .filter(|&end| end <= data.len())
+            .ok_or(invalid_sf2(
+                base + pos,
+                "chunk runs past the end of its list",
+            ))?;
+        chunks.push(Chunk {
+            id,
+            offset: base + begin,
+            data: &data[begin..end],
+        });
+        // Chunks are padded to an even length
+        pos = end + len % 2;
+    }
+    Ok(chunks)
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// Generators of a preset or instrument zone
+#[derive(Debug, Clone)]
+struct Zone {
+    generators: [Option<i16>; generator::COUNT],
+}
+
+impl Zone {
+    fn get(&self, generator: u16) -> Option<i16> {
+        self.generators.get(generator as usize).copied().flatten()
+    }
+
+    fn range(&self, generator: u16) -> (u8, u8) {
+        match self.get(generator) {
+            Some(value) => {
+                let [low, high] = value.to_le_bytes();
+                (low, high)
+            }
+            None => (0, 127),
+        }
+    }
+
+    fn contains(&self, key: u8, velocity: u8) -> bool {
+        let (key_low, key_high) = self.range(generator::KEY_RANGE);
+        let (vel_low, vel_high) = self.range(generator::VEL_RANGE);
+        (key_low..=key_high).contains(&key) && (vel_low..=vel_high).contains(&velocity)
+    }
+}
+
+/// Reads the zones of every preset or instrument from its bag and generator records.
+///
+/// The first zone of a preset or instrument is its global zone when it has no `terminal`
+/// generator, and its generators become the defaults of the other zones.
+fn read_zones(
+    header_bags: &[usize],
+    bags: &[&[u8]],
+    generators: &[&[u8]],
+    terminal: u16,
+    offset: usize,
+) -> Result<Vec<Vec<Zone>>> {
+    let bag_generators = |bag: usize| -> Result<Vec<(u16, i16)>> {
+        let begin = bags.get(bag).map(|b| u16_at(b, 0) as usize);
+        let end = bags.get(bag + 1).map(|b| u16_at(b, 0) as usize);
+        match (begin, end) {
+            (Some(begin), Some(end)) if begin <= end && end <= generators.len() => Ok(generators
+                [begin..end]
+                .iter()
+                .map(|g| (u16_at(g, 0), u16_at(g, 2) as i16))
+                .collect()),
+            _ => Err(invalid_sf2(offset, "zone points outside of its generators")),
+        }
+    };
+
+    let mut all = Vec::new();
+    for bounds in header_bags.windows(2) {
+        let mut global = None;
+        let mut zones = Vec::new();
+        for bag in bounds[0]..bounds[1].max(bounds[0]) {
+            let mut zone = global.clone().unwrap_or(Zone {
+                generators: [None; generator::COUNT],
+            });
+            let generators = bag_generators(bag)?;
+            for &(generator, amount) in &generators {
+                if let Some(slot) = zone.generators.get_mut(generator as usize) {
+                    *slot = Some(amount);
+                }
+            }
+            if generators.iter().any(|&(g, _)| g == terminal) {
+                zones.push(zone);
+            } else if bag == bounds[0] {
+                global = Some(zone);
+            }
+        }
+        all.push(zones);
+    }
+    Ok(all)
+}
+
+#[derive(Debug, Clone)]
+struct Preset {
+    bank: u16,
+    program: u16,
+    zones: Vec<Zone>,
+}
+
+#[derive(Debug, Clone)]
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+}
+
+/// An SF2 SoundFont, with the presets the synthesizer plays MIDI programs with
+#[derive(Debug, Clone)]
+pub struct SoundFont {
+    /// 16-bit samples of the `smpl` chunk, as floats
+    samples: Vec<f32>,
+    presets: Vec<Preset>,
+    instruments: Vec<Vec<Zone>>,
+    sample_headers: Vec<SampleHeader>,
+}
+
+impl SoundFont {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+            return Err(invalid_sf2(0, "not a RIFF sfbk file"));
+        }
+        let end = (u32_at(data, 4) as usize).saturating_add(8).min(data.len());
+        let mut samples = None;
+        let mut pdta = None;
+        for list in chunks(&data[12..end], 12)? {
+            if list.id != *b"LIST" || list.data.len() < 4 {
+                continue;
+            }
+            let sub_chunks = chunks(&list.data[4..], list.offset + 4)?;
+            match &list.data[..4] {
+                b"sdta" => samples = sub_chunks.into_iter().find(|c| c.id == *b"smpl"),
+                b"pdta" => pdta = Some(sub_chunks),
+                _ => {}
+            }
+        }
+        let samples = samples.ok_or(invalid_sf2(12, "no smpl chunk"))?;
+        let samples = samples
+            .data
+            .chunks_exact(2)
+            .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+            .collect::<Vec<_>>();
+        let pdta = pdta.ok_or(invalid_sf2(12, "no pdta list"))?;
+
+        // Every record list ends with a terminal record
+        let records = |id: &[u8; 4], size: usize| -> Result<(Vec<&[u8]>, usize)> {
+            let chunk = pdta
+                .iter()
+                .find(|c| c.id == *id)
+                .ok_or(invalid_sf2(12, "missing chunk in pdta list"))?;
+            let records = chunk.data.chunks_exact(size).collect::<Vec<_>>();
+            if records.len() < 2 {
+                return Err(invalid_sf2(chunk.offset, "too few records"));
+            }
+            Ok((records, chunk.offset))
+        };
+        let (phdr, _) = records(b"phdr", 38)?;
+        let (pbag, pbag_offset) = records(b"pbag", 4)?;
+        let (pgen, _) = records(b"pgen", 4)?;
+        let (inst, _) = records(b"inst", 22)?;
+        let (ibag, ibag_offset) = records(b"ibag", 4)?;
+        let (igen, _) = records(b"igen", 4)?;
+        let (shdr, _) = records(b"shdr", 46)?;
+
+        let preset_bags = phdr
+            .iter()
+            .map(|p| u16_at(p, 24) as usize)
+            .collect::<Vec<_>>();
+        let preset_zones = read_zones(
+            &preset_bags,
+            &pbag,
+            &pgen,
+            generator::INSTRUMENT,
+            pbag_offset,
+        )?;
+        let presets = phdr
+            .iter()
+            .zip(preset_zones)
+            .map(|(p, zones)| Preset {
+                program: u16_at(p, 20),
+                bank: u16_at(p, 22),
+                zones,
+            })
+            .collect();
+        let instrument_bags = inst
+            .iter()
+            .map(|i| u16_at(i, 20) as usize)
+            .collect::<Vec<_>>();
+        let instruments = read_zones(
+            &instrument_bags,
+            &ibag,
+            &igen,
+            generator::SAMPLE_ID,
+            ibag_offset,
+        )?;
+        let sample_headers = shdr[..shdr.len() - 1]
+            .iter()
+            .map(|s| SampleHeader {
+                start: u32_at(s, 20),
+                end: u32_at(s, 24),
+                loop_start: u32_at(s, 28),
+                loop_end: u32_at(s, 32),
+                sample_rate: u32_at(s, 36),
+                original_pitch: s[40],
+                pitch_correction: s[41] as i8,
+            })
+            .collect();
+
+        Ok(Self {
+            samples,
+            presets,
+            instruments,
+            sample_headers,
+        })
+    }
+
+    /// The preset of a bank and program, falling back to the same program in the first bank and
+    /// then to the first preset
+    fn preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        let find = |bank: u16, program: Option<u16>| {
+            self.presets
+                .iter()
+                .find(|p| p.bank == bank && program.is_none_or(|program| p.program == program))
+        };
+        find(bank, Some(program))
+            .or_else(|| match bank {
+                PERCUSSION_BANK => find(PERCUSSION_BANK, None),
+                _ => find(0, Some(program)),
+            })
+            .or(self.presets.first())
+    }
+}
+
+/// Options of [`render`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynthOptions {
+    pub(crate) sample_rate: u32,
+    reverb: bool,
+    chorus: bool,
+    gain: f32,
+}
+
+impl Default for SynthOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            reverb: true,
+            chorus: true,
+            gain: 0.5,
+        }
+    }
+}
+
+impl SynthOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frames per second of the rendered audio
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Sends the channels to a reverb, as much as their reverb controller asks for
+    pub fn reverb(mut self, reverb: bool) -> Self {
+        self.reverb = reverb;
+        self
+    }
+
+    /// Sends the channels to a chorus, as much as their chorus controller asks for
+    pub fn chorus(mut self, chorus: bool) -> Self {
+        self.chorus = chorus;
+        self
+    }
+
+    /// Volume of the mix, where 1 lets a single note at full velocity reach full scale
+    pub fn gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+}
+
+/// Seconds of a SoundFont time in timecents
+fn timecents_to_secs(timecents: i16) -> f32 {
+    2f32.powf(timecents as f32 / 1200.0)
+}
+
+/// Gain of an attenuation in centibels
+fn centibels_to_gain(centibels: f32) -> f32 {
+    10f32.powf(-centibels / 200.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// Volume envelope of a voice. The attack is linear, the decay and release fall by a fixed
+/// number of decibels per frame.
+#[derive(Debug, Clone)]
+struct Envelope {
+    stage: Stage,
+    /// Frames left in the delay, attack and hold stages
+    remaining: u32,
+    attack: u32,
+    hold: u32,
+    decay_factor: f32,
+    sustain: f32,
+    release_factor: f32,
+    gain: f32,
+}
+
+impl Envelope {
+    /// Envelope times in the SF2 specification are the time the level takes to fall by 100 dB
+    fn new(zone: &dyn Fn(u16, i16) -> i16, sample_rate: f32) -> Self {
+        let frames = |generator| (timecents_to_secs(zone(generator, -12000)) * sample_rate) as u32;
+        let factor = |generator| 1e-5f32.powf(1.0 / frames(generator).max(1) as f32);
+        let delay = frames(generator::DELAY_VOL_ENV);
+        Self {
+            stage: Stage::Delay,
+            remaining: delay,
+            attack: frames(generator::ATTACK_VOL_ENV),
+            hold: frames(generator::HOLD_VOL_ENV),
+            decay_factor: factor(generator::DECAY_VOL_ENV),
+            sustain: centibels_to_gain(zone(generator::SUSTAIN_VOL_ENV, 0).max(0) as f32),
+            release_factor: factor(generator::RELEASE_VOL_ENV),
+            gain: 0.0,
+        }
+    }
+
+    fn release(&mut self) {
+        if self.stage != Stage::Done {
+            self.stage = Stage::Release;
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        loop {
+            match self.stage {
+                Stage::Delay | Stage::Attack | Stage::Hold if self.remaining == 0 => {
+                    (self.stage, self.remaining) = match self.stage {
+                        Stage::Delay => (Stage::Attack, self.attack),
+                        Stage::Attack => (Stage::Hold, self.hold),
+                        _ => (Stage::Decay, 0),
+                    };
+                    if self.stage != Stage::Attack {
+                        self.gain = 1.0;
+                    }
+                }
+                Stage::Delay | Stage::Hold => {
+                    self.remaining -= 1;
+                    return self.gain;
+                }
+                Stage::Attack => {
+                    self.gain = 1.0 - self.remaining as f32 / self.attack as f32;
+                    self.remaining -= 1;
+                    return self.gain;
+                }
+                Stage::Decay => {
+                    self.gain *= self.decay_factor;
+                    if self.gain <= self.sustain {
+                        self.gain = self.sustain;
+                        self.stage = Stage::Sustain;
+                    }
+                    return self.gain;
+                }
+                Stage::Sustain => return self.gain,
+                Stage::Release => {
+                    self.gain *= self.release_factor;
+                    if self.gain < 1e-5 {
+                        self.stage = Stage::Done;
+                    }
+                    return self.gain;
+                }
+                Stage::Done => return 0.0,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    exclusive_class: i16,
+    /// Position in the samples of the SoundFont
+    position: f64,
+    /// Samples to advance per frame, before pitch bend
+    step: f64,
+    end: f64,
+    loop_start: f64,
+    loop_end: f64,
+    /// 1 loops while the note is held and after, 3 only while the note is held
+    sample_mode: i16,
+    gain: f32,
+    /// -0.5 for left to 0.5 for right
+    pan: f32,
+    reverb: f32,
+    chorus: f32,
+    envelope: Envelope,
+    /// Note off came while the sustain pedal was down
+    sustained: bool,
+}
+
+impl Voice {
+    fn released(&self) -> bool {
+        matches!(self.envelope.stage, Stage::Release | Stage::Done)
+    }
+
+    fn release(&mut self) {
+        self.sustained = false;
+        self.envelope.release();
+    }
+
+    /// Next sample of the voice, or `None` when it has ended
+    fn next(&mut self, samples: &[f32], bend: f64) -> Option<f32> {
+        let looping = self.sample_mode == 1 || (self.sample_mode == 3 && !self.released());
+        if looping && self.position >= self.loop_end && self.loop_end > self.loop_start {
+            self.position -= self.loop_end - self.loop_start;
+        }
+        if self.position >= self.end || self.envelope.stage == Stage::Done {
+            return None;
+        }
+        let index = self.position as usize;
+        let fraction = (self.position - index as f64) as f32;
+        let current = samples.get(index).copied().unwrap_or(0.0);
+        let next = samples.get(index + 1).copied().unwrap_or(0.0);
+        self.position += self.step * bend;
+        Some((current + (next - current) * fraction) * self.envelope.next())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    bank: u16,
+    program: u16,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    sustain: bool,
+    reverb: u8,
+    chorus: u8,
+    /// 14-bit pitch bend, 0x2000 is the centre
+    pitch_bend: u16,
+    /// Semitones of a full pitch bend
+    bend_range: u8,
+    /// Registered parameter selected with controllers 101 and 100
+    rpn: (u8, u8),
+}
+
+impl Channel {
+    fn new(channel: u8) -> Self {
+        Self {
+            bank: if channel == PERCUSSION_CHANNEL {
+                PERCUSSION_BANK
+            } else {
+                0
+            },
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            sustain: false,
+            reverb: 40,
+            chorus: 0,
+            pitch_bend: 0x2000,
+            bend_range: 2,
+            rpn: (127, 127),
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        let volume = self.volume as f32 / 127.0;
+        let expression = self.expression as f32 / 127.0;
+        volume * volume * expression * expression
+    }
+
+    fn bend(&self) -> f64 {
+        let semitones = (self.pitch_bend as f64 - 8192.0) / 8192.0 * self.bend_range as f64;
+        2f64.powf(semitones / 12.0)
+    }
+}
+
+/// Freeverb, a network of comb and all-pass filters
+#[derive(Debug, Clone)]
+struct Reverb {
+    combs: [Vec<(Vec<f32>, usize, f32)>; 2],
+    allpasses: [Vec<(Vec<f32>, usize)>; 2],
+}
+
+impl Reverb {
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    const STEREO_SPREAD: usize = 23;
+    const FEEDBACK: f32 = 0.84;
+    const DAMP: f32 = 0.2;
+
+    fn new(sample_rate: u32) -> Self {
+        // The delays are tuned for 44.1 kHz
+        let scale = |len: usize, side: usize| {
+            let len = (len + side * Self::STEREO_SPREAD) as u64 * sample_rate as u64 / 44100;
+            len.max(1) as usize
+        };
+        let combs = |side| {
+            Self::COMBS
+                .iter()
+                .map(|&len| (vec![0.0; scale(len, side)], 0, 0.0))
+                .collect()
+        };
+        let allpasses = |side| {
+            Self::ALLPASSES
+                .iter()
+                .map(|&len| (vec![0.0; scale(len, side)], 0))
+                .collect()
+        };
+        Self {
+            combs: [combs(0), combs(1)],
+            allpasses: [allpasses(0), allpasses(1)],
+        }
+    }
+
+    fn process(&mut self, input: f32) -> [f32; 2] {
+        let input = input * 0.015;
+        let mut out = [0.0; 2];
+        for (side, out) in out.iter_mut().enumerate() {
+            for (buffer, index, store) in &mut self.combs[side] {
+                let delayed = buffer[*index];
+                *store = delayed * (1.0 - Self::DAMP) + *store * Self::DAMP;
+                buffer[*index] = input + *store * Self::FEEDBACK;
+                *index = (*index + 1) % buffer.len();
+                *out += delayed;
+            }
+            for (buffer, index) in &mut self.allpasses[side] {
+                let delayed = buffer[*index];
+                buffer[*index] = *out + delayed * 0.5;
+                *index = (*index + 1) % buffer.len();
+                *out = delayed - *out;
+            }
+        }
+        out
+    }
+}
+
+/// A delay line swept by a slow sine, read with a different phase on either side
+#[derive(Debug, Clone)]
+struct Chorus {
+    buffer: Vec<f32>,
+    index: usize,
+    phase: f32,
+    phase_step: f32,
+    sample_rate: f32,
+}
+
+impl Chorus {
+    const DELAY: f32 = 0.015;
+    const DEPTH: f32 = 0.004;
+    const RATE: f32 = 0.4;
+
+    fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        let len = ((Self::DELAY + Self::DEPTH) * sample_rate) as usize + 2;
+        Self {
+            buffer: vec![0.0; len],
+            index: 0,
+            phase: 0.0,
+            phase_step: TAU * Self::RATE / sample_rate,
+            sample_rate,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> [f32; 2] {
+        let len = self.buffer.len();
+        self.buffer[self.index] = input;
+        let tap = |phase: f32| {
+            let delay = (Self::DELAY + Self::DEPTH * phase.sin()) * self.sample_rate;
+            let position = self.index as f32 + len as f32 - delay;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = self.buffer[index % len];
+            let next = self.buffer[(index + 1) % len];
+            current + (next - current) * fraction
+        };
+        let out = [tap(self.phase), tap(self.phase + FRAC_PI_2)];
+        self.index = (self.index + 1) % len;
+        self.phase = (self.phase + self.phase_step) % TAU;
+        out
+    }
+}
+
+struct Synth<'a> {
+    font: &'a SoundFont,
+    options: &'a SynthOptions,
+    channels: Vec<Channel>,
+    voices: Vec<Voice>,
+    reverb: Option<Reverb>,
+    chorus: Option<Chorus>,
+}
+
+impl<'a> Synth<'a> {
+    fn new(font: &'a SoundFont, options: &'a SynthOptions) -> Self {
+        Self {
+            font,
+            options,
+            channels: (0..16).map(Channel::new).collect(),
+            voices: Vec::new(),
+            reverb: options.reverb.then(|| Reverb::new(options.sample_rate)),
+            chorus: options.chorus.then(|| Chorus::new(options.sample_rate)),
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let state = &self.channels[channel as usize];
+        let Some(preset) = self.font.preset(state.bank, state.program) else {
+            return;
+        };
+        let sample_rate = self.options.sample_rate as f32;
+        let mut voices = Vec::new();
+        for preset_zone in preset.zones.iter().filter(|z| z.contains(key, velocity)) {
+            let instrument = preset_zone
+                .get(generator::INSTRUMENT)
+                .and_then(|i| self.font.instruments.get(i as u16 as usize));
+            let zones = instrument.into_iter().flatten();
+            for zone in zones.filter(|z| z.contains(key, velocity)) {
+                let Some(sample) = zone
+                    .get(generator::SAMPLE_ID)
+                    .and_then(|s| self.font.sample_headers.get(s as u16 as usize))
+                else {
+                    continue;
+                };
+                // Preset generators add to the instrument ones
+                let value = |generator, default: i16| {
+                    let instrument = zone.get(generator).unwrap_or(default) as i32;
+                    let preset = preset_zone.get(generator).unwrap_or(0) as i32;
+                    (instrument + preset).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+                };
+                let address = |base: u32, fine, coarse| {
+                    base as f64 + value(fine, 0) as f64 + value(coarse, 0) as f64 * 32768.0
+                };
+
+                let root = match zone.get(generator::OVERRIDING_ROOT_KEY) {
+                    Some(root @ 0..=127) => root as f64,
+                    _ if sample.original_pitch <= 127 => sample.original_pitch as f64,
+                    _ => 60.0,
+                };
+                let cents = (key as f64 - root) * value(generator::SCALE_TUNING, 100) as f64
+                    + value(generator::COARSE_TUNE, 0) as f64 * 100.0
+                    + value(generator::FINE_TUNE, 0) as f64
+                    + sample.pitch_correction as f64;
+                let step = 2f64.powf(cents / 1200.0) * sample.sample_rate as f64
+                    / self.options.sample_rate as f64;
+
+                let velocity = velocity as f32 / 127.0;
+                let attenuation = value(generator::INITIAL_ATTENUATION, 0).max(0) as f32;
+                let send = |generator| value(generator, 0).clamp(0, 1000) as f32 / 1000.0;
+                voices.push(Voice {
+                    channel,
+                    key,
+                    exclusive_class: zone.get(generator::EXCLUSIVE_CLASS).unwrap_or(0),
+                    position: address(
+                        sample.start,
+                        generator::START_OFFSET,
+                        generator::START_COARSE_OFFSET,
+                    ),
+                    step,
+                    end: address(
+                        sample.end,
+                        generator::END_OFFSET,
+                        generator::END_COARSE_OFFSET,
+                    ),
+                    loop_start: address(
+                        sample.loop_start,
+                        generator::LOOP_START_OFFSET,
+                        generator::LOOP_START_COARSE_OFFSET,
+                    ),
+                    loop_end: address(
+                        sample.loop_end,
+                        generator::LOOP_END_OFFSET,
+                        generator::LOOP_END_COARSE_OFFSET,
+                    ),
+                    sample_mode: zone.get(generator::SAMPLE_MODES).unwrap_or(0) & 3,
+                    gain: velocity * velocity * centibels_to_gain(attenuation),
+                    pan: value(generator::PAN, 0).clamp(-500, 500) as f32 / 1000.0,
+                    reverb: send(generator::REVERB_SEND),
+                    chorus: send(generator::CHORUS_SEND),
+                    envelope: Envelope::new(&value, sample_rate),
+                    sustained: false,
+                });
+            }
+        }
+
+        for voice in &voices {
+            // A hi-hat opening cuts off the closed one
+            if voice.exclusive_class != 0 {
+                self.voices
+                    .retain(|v| v.channel != channel || v.exclusive_class != voice.exclusive_class);
+            }
+        }
+        // A key struck again ends the note it was playing
+        self.note_off(channel, key, true);
+        self.voices.extend(voices);
+        if self.voices.len() > MAX_VOICES {
+            let excess = self.voices.len() - MAX_VOICES;
+            self.voices.drain(..excess);
+        }
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8, force: bool) {
+        let sustain = self.channels[channel as usize].sustain && !force;
+        for voice in &mut self.voices {
+            if voice.channel == channel && voice.key == key && !voice.released() {
+                if sustain {
+                    voice.sustained = true;
+                } else {
+                    voice.release();
+                }
+            }
+        }
+    }
+
+    fn controller(&mut self, channel: u8, controller: u8, value: u8) {
+        let state = &mut self.channels[channel as usize];
+        match controller {
+            0 if channel != PERCUSSION_CHANNEL => state.bank = value as u16,
+            6 if state.rpn == (0, 0) => state.bend_range = value,
+            7 => state.volume = value,
+            10 => state.pan = value,
+            11 => state.expression = value,
+            64 => {
+                state.sustain = value >= 64;
+                if !state.sustain {
+                    self.voices
+                        .iter_mut()
+                        .filter(|v| v.channel == channel && v.sustained)
+                        .for_each(Voice::release);
+                }
+            }
+            91 => state.reverb = value,
+            93 => state.chorus = value,
+            100 => state.rpn.1 = value,
+            101 => state.rpn.0 = value,
+            120 => self.voices.retain(|v| v.channel != channel),
+            121 => {
+                state.expression = 127;
+                state.sustain = false;
+                state.pitch_bend = 0x2000;
+                state.rpn = (127, 127);
+            }
+            123 => self
+                .voices
+                .iter_mut()
+                .filter(|v| v.channel == channel)
+                .for_each(Voice::release),
+            _ => {}
+        }
+    }
+
+    /// Applies an event. Before the start of the rendered range, notes are not played but the
+    /// state of the channels is kept up to date.
+    fn handle(&mut self, kind: &EventKind, playing: bool) {
+        let EventKind::Channel { channel, message } = kind else {
+            return;
+        };
+        let channel = *channel & 0x0F;
+        match *message {
+            ChannelMessage::NoteOn { key, velocity } if velocity > 0 && playing => {
+                self.note_on(channel, key, velocity)
+            }
+            // Notes before the rendered range are skipped
+            ChannelMessage::NoteOn { velocity, .. } if velocity > 0 => {}
+            ChannelMessage::NoteOn { key, .. } | ChannelMessage::NoteOff { key, .. } => {
+                self.note_off(channel, key, false)
+            }
+            ChannelMessage::Controller { controller, value } => {
+                self.controller(channel, controller, value)
+            }
+            ChannelMessage::ProgramChange { program } => {
+                self.channels[channel as usize].program = program as u16
+            }
+            ChannelMessage::PitchBend { value } => {
+                self.channels[channel as usize].pitch_bend = value
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends `frames` interleaved stereo frames to `out`
+    fn render(&mut self, out: &mut Vec<f32>, frames: usize) {
+        let begin = out.len();
+        out.resize(begin + frames * 2, 0.0);
+        let out = &mut out[begin..];
+        let mut reverb = vec![0.0; frames];
+        let mut chorus = vec![0.0; frames];
+
+        for voice in &mut self.voices {
+            let channel = &self.channels[voice.channel as usize];
+            let bend = channel.bend();
+            let gain = voice.gain * channel.gain() * self.options.gain;
+            let pan = (voice.pan + (channel.pan as f32 - 64.0) / 128.0).clamp(-0.5, 0.5);
+            let (left, right) = (
+                gain * ((pan + 0.5) * FRAC_PI_2).cos(),
+                gain * ((pan + 0.5) * FRAC_PI_2).sin(),
+            );
+            let reverb_send = (voice.reverb + channel.reverb as f32 / 127.0).min(1.0) * gain;
+            let chorus_send = (voice.chorus + channel.chorus as f32 / 127.0).min(1.0) * gain;
+            for frame in 0..frames {
+                let Some(sample) = voice.next(&self.font.samples, bend) else {
+                    voice.envelope.stage = Stage::Done;
+                    break;
+                };
+                out[frame * 2] += sample * left;
+                out[frame * 2 + 1] += sample * right;
+                reverb[frame] += sample * reverb_send;
+                chorus[frame] += sample * chorus_send;
+            }
+        }
+        self.voices.retain(|v| v.envelope.stage != Stage::Done);
+
+        if let Some(effect) = &mut self.reverb {
+            for (frame, input) in reverb.into_iter().enumerate() {
+                let [left, right] = effect.process(input);
+                out[frame * 2] += left;
+                out[frame * 2 + 1] += right;
+            }
+        }
+        if let Some(effect) = &mut self.chorus {
+            for (frame, input) in chorus.into_iter().enumerate() {
+                let [left, right] = effect.process(input);
+                out[frame * 2] += left;
+                out[frame * 2 + 1] += right;
+            }
+        }
+    }
+}
+
+/// Renders a song to interleaved stereo samples with the presets of a SoundFont.
+///
+/// Only the part from `start_ms` to `stop_ms` is rendered. Without `stop_ms`, rendering goes on
+/// after the last event until the last notes have faded, for at most 3 seconds.
+pub fn render(
+    song: &MidiSong,
+    font: &SoundFont,
+    options: &SynthOptions,
+    start_ms: u32,
+    stop_ms: Option<u32>,
+) -> Result<Vec<f32>> {
+    if options.sample_rate == 0 {
+        return Err(EmkError::InvalidConversion {
+            value: "0".to_string(),
+            target: "sample rate",
+        });
+    }
+    let time_base = TimeBase::from_song(song);
+    let frame = |ms: f64| (ms * options.sample_rate as f64 / 1000.0).round() as u64;
+    let start = frame(start_ms as f64);
+    let stop = stop_ms.map(|ms| frame(ms as f64));
+
+    // Tracks are played together, and events at the same tick keep their order
+    let mut events = song
+        .tracks
+        .iter()
+        .flat_map(|track| track.timed_events())
+        .map(|(tick, event)| (tick, &event.kind))
+        .collect::<Vec<_>>();
+    events.sort_by_key(|&(tick, _)| tick);
+
+    let mut synth = Synth::new(font, options);
+    let mut out = Vec::new();
+    let mut now = start;
+    for (tick, kind) in events {
+        let at = frame(time_base.ticks_to_ms(tick));
+        if stop.is_some_and(|stop| at >= stop) {
+            break;
+        }
+        if at > now {
+            synth.render(&mut out, (at - now) as usize);
+            now = at;
+        }
+        synth.handle(kind, at >= start);
+    }
+
+    let end = stop.unwrap_or(now + frame(MAX_TAIL as f64));
+    while now < end {
+        let frames = (end - now).min(1024);
+        synth.render(&mut out, frames as usize);
+        now += frames;
+        if stop.is_none() && synth.voices.is_empty() {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/// Writes interleaved stereo samples as a 16-bit PCM WAV file. Fails if the sample rate or the
+/// length of the samples does not fit in the 32-bit fields of the header.
+pub fn to_wav(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+    let byte_rate =
+        sample_rate
+            .checked_mul(block_align as u32)
+            .ok_or_else(|| EmkError::InvalidConversion {
+                value: sample_rate.to_string(),
+                target: "WAV sample rate",
+            })?;
+    let too_long = || EmkError::InvalidConversion {
+        value: format!("{} samples", samples.len()),
+        target: "WAV file",
+    };
+    let data_len = samples
+        .len()
+        .checked_mul(BYTES_PER_SAMPLE as usize)
+        .and_then(|len| u32::try_from(len).ok())
+        .ok_or_else(too_long)?;
+    let riff_len = data_len.checked_add(36).ok_or_else(too_long)?;
+
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&riff_len.to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&CHANNELS.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&byte_rate.to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, to_wav, SoundFont, SynthOptions};
+    use crate::midi::{ChannelMessage, EventKind, MidiSong, Track};
+    use crate::test_util::sample;
+
+    static SOUND_FONT: &[u8] = include_bytes!("../examples/sine.sf2");
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_parse_sound_font() {
+        let font = SoundFont::parse(SOUND_FONT).unwrap();
+        assert_eq!(font.presets.len(), 1);
+        assert_eq!(font.presets[0].zones.len(), 1);
+        assert_eq!(font.instruments[0].len(), 1);
+        assert_eq!(font.sample_headers[0].loop_end, 64);
+        // Whatever the bank and program, the only preset plays
+        assert_eq!(font.preset(128, 5).unwrap().program, 0);
+
+        assert!(SoundFont::parse(b"RIFF\0\0\0\0WAVE").is_err());
+        for len in 0..SOUND_FONT.len() {
+            let _ = SoundFont::parse(&SOUND_FONT[..len]);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let font = SoundFont::parse(SOUND_FONT).unwrap();
+        let mut file = sample();
+        let song_info = file.song_info_mut().unwrap();
+        song_info.start_time = 10_000;
+        song_info.stop_time = 12_000;
+
+        let options = SynthOptions::new().sample_rate(8000);
+        let pcm = file.render_pcm(&font, &options).unwrap();
+        assert_eq!(pcm.len(), 2 * 2 * 8000);
+        assert!(rms(&pcm) > 0.01);
+        assert!(pcm.iter().all(|s| s.is_finite()));
+
+        let dry = file
+            .render_pcm(&font, &options.clone().reverb(false).chorus(false))
+            .unwrap();
+        assert_eq!(dry.len(), pcm.len());
+        assert_ne!(dry, pcm);
+
+        let wav = file.render_wav(&font, &options).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 8000);
+        assert_eq!(wav.len(), 44 + pcm.len() * 2);
+        assert_eq!(to_wav(&pcm, 8000).unwrap(), wav);
+        // The byte rate of the header would overflow
+        assert!(to_wav(&pcm, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_pitch() {
+        let font = SoundFont::parse(SOUND_FONT).unwrap();
+        let note = |tick, velocity| {
+            let message = ChannelMessage::NoteOn { key: 69, velocity };
+            (
+                tick,
+                EventKind::Channel {
+                    channel: 0,
+                    message,
+                },
+            )
+        };
+        let song = MidiSong {
+            format: 0,
+            ppq: 96,
+            tracks: vec![Track::from_timed_events([note(0, 127), note(96, 0)])],
+        };
+        let options = SynthOptions::new()
+            .sample_rate(8000)
+            .reverb(false)
+            .chorus(false);
+        let pcm = render(&song, &font, &options, 0, None).unwrap();
+
+        // A4 sounds for half a second at 120 BPM, then fades within the release
+        let left = pcm.iter().step_by(2).copied().collect::<Vec<_>>();
+        let crossings = left[..3200]
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count();
+        assert!((350..=354).contains(&crossings), "{crossings}");
+        assert!(left.len() > 4000 && left.len() < 6000);
+        assert!(rms(&left[left.len() - 100..]) < 1e-3);
+    }
+}
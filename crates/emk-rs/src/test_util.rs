@@ -0,0 +1,9 @@
+use crate::types::EmkFile;
+
+/// `000001.emk`, encrypted with the default key
+pub static TEST_DATA: &[u8] = include_bytes!("../examples/000001.emk");
+
+/// The sample file, parsed
+pub fn sample() -> EmkFile {
+    EmkFile::from_bytes(TEST_DATA).unwrap()
+}
@@ -0,0 +1,152 @@
+use crate::cursor::Cursor;
+use crate::error::Result;
+use crate::midi::MidiSong;
+use crate::timed::{self, TimedLine};
+
+/// Tempo of a MIDI file without tempo events, 120 BPM
+pub(crate) const DEFAULT_TEMPO: u32 = 500_000;
+
+/// A tempo change of the tempo map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoChange {
+    pub tick: u32,
+    pub micros_per_quarter: u32,
+    /// Time of the change, in milliseconds from the start of the song
+    pub millis: f64,
+}
+
+/// Converts between MIDI ticks, cursor units and milliseconds, following the tempo map of a MIDI
+/// file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeBase {
+    ppq: u16,
+    /// Tempo changes sorted by tick, always starting at tick 0
+    tempos: Vec<TempoChange>,
+}
+
+impl TimeBase {
+    /// Builds a time base from `(tick, microseconds per quarter note)` tempo changes
+    pub fn new(ppq: u16, tempos: impl IntoIterator<Item = (u32, u32)>) -> Self {
+        let mut changes = tempos.into_iter().collect::<Vec<_>>();
+        changes.sort_by_key(|&(tick, _)| tick);
+        if changes.first().is_none_or(|&(tick, _)| tick > 0) {
+            changes.insert(0, (0, DEFAULT_TEMPO));
+        }
+
+        let ppq = ppq.max(1);
+        let mut tempos: Vec<TempoChange> = Vec::with_capacity(changes.len());
+        for (tick, micros_per_quarter) in changes {
+            let millis = tempos.last().map_or(0.0, |prev| {
+                prev.millis + ticks_in_tempo(tick - prev.tick, prev.micros_per_quarter, ppq)
+            });
+            // A later change at the same tick replaces the earlier one
+            if tempos.last().is_some_and(|prev| prev.tick == tick) {
+                tempos.pop();
+            }
+            tempos.push(TempoChange {
+                tick,
+                micros_per_quarter,
+                millis,
+            });
+        }
+        Self { ppq, tempos }
+    }
+
+    /// Reads the PPQ and the tempo map of a Standard MIDI File
+    pub fn from_midi(data: &[u8]) -> Result<Self> {
+        Ok(Self::from_song(&MidiSong::parse(data)?))
+    }
+
+    pub fn from_song(song: &MidiSong) -> Self {
+        Self::new(song.ppq, song.tempo_changes())
+    }
+
+    /// Ticks per quarter note
+    pub fn ppq(&self) -> u16 {
+        self.ppq
+    }
+
+    pub fn tempos(&self) -> &[TempoChange] {
+        &self.tempos
+    }
+
+    pub fn ticks_to_ms(&self, ticks: u32) -> f64 {
+        let tempo = self.tempos.iter().rev().find(|t| t.tick <= ticks);
+        // The tempo map always starts at tick 0
+        let tempo = tempo.unwrap_or(&self.tempos[0]);
+        tempo.millis + ticks_in_tempo(ticks - tempo.tick, tempo.micros_per_quarter, self.ppq)
+    }
+
+    pub fn ms_to_ticks(&self, ms: f64) -> u32 {
+        let tempo = self.tempos.iter().rev().find(|t| t.millis <= ms);
+        let tempo = tempo.unwrap_or(&self.tempos[0]);
+        let ticks =
+            (ms - tempo.millis) * 1000.0 * self.ppq as f64 / tempo.micros_per_quarter as f64;
+        // The cast saturates, and so must the sum for times past the end of the song
+        tempo.tick.saturating_add(ticks.round().max(0.0) as u32)
+    }
+
+    pub fn cursor_to_ticks(&self, value: u32) -> u32 {
+        (value as u64 * self.ppq as u64 / Cursor::UNITS_PER_QUARTER as u64) as u32
+    }
+
+    pub fn ticks_to_cursor(&self, ticks: u32) -> u32 {
+        let units = ticks as u64 * Cursor::UNITS_PER_QUARTER as u64;
+        ((units + self.ppq as u64 / 2) / self.ppq as u64) as u32
+    }
+
+    pub fn cursor_to_ms(&self, value: u32) -> f64 {
+        self.ticks_to_ms(self.cursor_to_ticks(value))
+    }
+
+    pub fn ms_to_cursor(&self, ms: f64) -> u32 {
+        self.ticks_to_cursor(self.ms_to_ticks(ms))
+    }
+
+    /// Converts the timings of [`EmkFile::timed_lyrics`](crate::types::EmkFile::timed_lyrics)
+    /// from cursor units to milliseconds
+    pub fn lines_to_ms(&self, lines: &[TimedLine]) -> Vec<TimedLine> {
+        timed::map_times(lines, |value| self.cursor_to_ms(value).round() as u32)
+    }
+
+    /// Converts the timings of [`EmkFile::timed_lyrics`](crate::types::EmkFile::timed_lyrics)
+    /// from cursor units to MIDI ticks
+    pub fn lines_to_ticks(&self, lines: &[TimedLine]) -> Vec<TimedLine> {
+        timed::map_times(lines, |value| self.cursor_to_ticks(value))
+    }
+}
+
+/// Length of `ticks` at a constant tempo, in milliseconds
+fn ticks_in_tempo(ticks: u32, micros_per_quarter: u32, ppq: u16) -> f64 {
+    ticks as f64 * micros_per_quarter as f64 / ppq as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeBase;
+    use crate::test_util::sample;
+
+    #[test]
+    fn test_time_base_from_midi() {
+        let file = sample();
+        let time_base = file.time_base().unwrap();
+        assert_eq!(time_base.ppq(), 96);
+        // 140 BPM, like SongInfo::tempo says
+        let bpm = 60_000_000.0 / time_base.tempos()[0].micros_per_quarter as f64;
+        assert_eq!(bpm.round() as u32, 140);
+
+        assert_eq!(time_base.cursor_to_ticks(24), 96);
+        assert!((time_base.cursor_to_ms(24) - 428.571).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_tempo_changes() {
+        let time_base = TimeBase::new(96, [(96, 250_000), (0, 500_000)]);
+        assert_eq!(time_base.ticks_to_ms(96), 500.0);
+        assert_eq!(time_base.ticks_to_ms(192), 750.0);
+        assert_eq!(time_base.ms_to_ticks(750.0), 192);
+        assert_eq!(time_base.ms_to_ticks(250.0), 48);
+        assert_eq!(time_base.ms_to_cursor(750.0), 48);
+        assert_eq!(time_base.ms_to_ticks(1e13), u32::MAX);
+    }
+}
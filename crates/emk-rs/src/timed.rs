@@ -0,0 +1,218 @@
+use crate::cursor::Cursor;
+use crate::error::Result;
+use crate::lyrics::{cells, Lyrics};
+
+/// A piece of a lyric line that is highlighted at once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedSyllable {
+    pub text: String,
+    /// Time the highlight reaches the syllable, in cursor units or milliseconds
+    pub start: u32,
+    /// Time the highlight reaches the next syllable, in cursor units or milliseconds
+    pub end: u32,
+}
+
+/// A sung line of the lyrics, split into syllables
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TimedLine {
+    pub syllables: Vec<TimedSyllable>,
+}
+
+impl TimedLine {
+    pub fn text(&self) -> String {
+        self.syllables.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    /// Start of the first syllable, if the line is not empty
+    pub fn start(&self) -> Option<u32> {
+        self.syllables.first().map(|s| s.start)
+    }
+
+    /// End of the last syllable, if the line is not empty
+    pub fn end(&self) -> Option<u32> {
+        self.syllables.last().map(|s| s.end)
+    }
+}
+
+/// Pairs every lyric cell with its cursor timing.
+///
+/// Thai above/below vowels and tone marks stay in the cell of their base character, and cells
+/// with the same timing are joined into one syllable. Every sung line gets a [`TimedLine`], even
+/// when it is empty, so the result lines up with [`Lyrics::lines`].
+pub fn align(lyrics: &Lyrics, cursor: &Cursor) -> Result<Vec<TimedLine>> {
+    cursor.validate(lyrics)?;
+
+    let mut cells = lyrics
+        .lines()
+        .iter()
+        .enumerate()
+        .flat_map(|(line, text)| cells(text).map(move |cell| (line, cell)))
+        .zip(cursor.values().iter().map(|&v| v as u32))
+        .peekable();
+
+    let mut lines = vec![TimedLine::default(); lyrics.lines().len()];
+    while let Some(((line, text), start)) = cells.next() {
+        // The highlight of the last cell ends where it starts
+        let end = cells.peek().map_or(start, |&(_, next)| next.max(start));
+        let syllables = &mut lines[line].syllables;
+        match syllables.last_mut() {
+            Some(last) if last.start == start => {
+                last.text.push_str(text);
+                last.end = end;
+            }
+            _ => syllables.push(TimedSyllable {
+                text: text.to_string(),
+                start,
+                end,
+            }),
+        }
+    }
+    Ok(lines)
+}
+
+/// Converts the timings of timed lines, for example from cursor units to milliseconds
+pub fn map_times(lines: &[TimedLine], f: impl Fn(u32) -> u32) -> Vec<TimedLine> {
+    lines
+        .iter()
+        .map(|line| TimedLine {
+            syllables: line
+                .syllables
+                .iter()
+                .map(|s| TimedSyllable {
+                    text: s.text.clone(),
+                    start: f(s.start),
+                    end: f(s.end),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Builds a cursor from lines timed in cursor units, the reverse of [`align`]. Every cell of a
+/// syllable gets the start of the syllable.
+pub fn to_cursor(lines: &[TimedLine]) -> Cursor {
+    let mut values = Vec::new();
+    for line in lines {
+        for (index, _) in syllable_cells(line) {
+            let value = line.syllables[index].start.min(u16::MAX as u32);
+            values.push(value as u16);
+        }
+    }
+    Cursor {
+        values,
+        trailer: Vec::new(),
+    }
+}
+
+/// Splits every syllable of a line into its cells, spread evenly from the start of the syllable
+/// to its end. Used for lines without word timing, so the highlight moves through the line.
+pub fn split_cells(line: &TimedLine) -> TimedLine {
+    let mut syllables = Vec::<TimedSyllable>::new();
+    let mut cells = syllable_cells(line).peekable();
+    while let Some((index, cell)) = cells.next() {
+        let mut group = vec![cell];
+        while let Some((_, cell)) = cells.next_if(|&(next, _)| next == index) {
+            group.push(cell);
+        }
+        let syllable = &line.syllables[index];
+        let length = syllable.end.saturating_sub(syllable.start) as u64;
+        let count = group.len() as u64;
+        let at = |k: u64| (syllable.start as u64 + length * k / count) as u32;
+        for (k, cell) in (0..).zip(group) {
+            syllables.push(TimedSyllable {
+                text: cell.to_string(),
+                start: at(k),
+                end: at(k + 1),
+            });
+        }
+    }
+    TimedLine { syllables }
+}
+
+/// The cells of a line, with the index of the syllable each cell starts in
+fn syllable_cells(line: &TimedLine) -> impl Iterator<Item = (usize, String)> {
+    // Byte offset where every syllable of the line starts
+    let mut offset = 0;
+    let starts = line
+        .syllables
+        .iter()
+        .map(|s| {
+            let start = offset;
+            offset += s.text.len();
+            start
+        })
+        .collect::<Vec<_>>();
+    let text = line.text();
+    let mut pos = 0;
+    cells(&text)
+        .map(|cell| {
+            let index = starts.partition_point(|&s| s <= pos) - 1;
+            pos += cell.len();
+            (index, cell.to_string())
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align, split_cells, to_cursor};
+    use crate::cursor::Cursor;
+    use crate::lyrics::Lyrics;
+    use crate::test_util::sample;
+
+    #[test]
+    fn test_thai_clusters() {
+        // "ที่นี่ ไป" in TIS-620: two cells for "ที่นี่", a space, and two cells for "ไป"
+        let lyrics = Lyrics::decode(b"T\r\nA\r\nC\r\n\r\n\xB7\xD5\xE8\xB9\xD5\xE8 \xE4\xBB");
+        let cursor = Cursor::parse(&[10, 0, 20, 0, 30, 0, 30, 0, 40, 0]);
+        let lines = align(&lyrics, &cursor).unwrap();
+
+        let syllables = lines[0]
+            .syllables
+            .iter()
+            .map(|s| (s.text.as_str(), s.start, s.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            syllables,
+            [("ที่", 10, 20), ("นี่", 20, 30), (" ไ", 30, 40), ("ป", 40, 40)]
+        );
+    }
+
+    #[test]
+    fn test_timed_lyrics() {
+        let file = sample();
+        let lines = file.timed_lyrics().unwrap();
+        let lyrics = file.lyrics().unwrap();
+        assert_eq!(lines.len(), lyrics.lines().len());
+        for (line, text) in lines.iter().zip(lyrics.lines()) {
+            assert_eq!(&line.text(), text);
+        }
+        assert_eq!(lines[0].start(), Some(11));
+    }
+
+    #[test]
+    fn test_to_cursor() {
+        let file = sample();
+        let cursor = file.cursor().unwrap();
+        let cells = file.lyrics().unwrap().cell_count();
+        let rebuilt = to_cursor(&file.timed_lyrics().unwrap());
+        assert_eq!(rebuilt.values(), &cursor.values()[..cells]);
+    }
+
+    #[test]
+    fn test_split_cells() {
+        let lyrics = Lyrics::decode(b"T\r\nA\r\nC\r\n\r\nab\xB7\xD5\xE8");
+        let mut lines = align(&lyrics, &Cursor::parse(&[10, 0, 10, 0, 10, 0])).unwrap();
+        lines[0].syllables[0].end = 19;
+
+        let split = split_cells(&lines[0]);
+        let syllables = split
+            .syllables
+            .iter()
+            .map(|s| (s.text.as_str(), s.start, s.end))
+            .collect::<Vec<_>>();
+        assert_eq!(syllables, [("a", 10, 13), ("b", 13, 16), ("ที่", 16, 19)]);
+        assert_eq!(to_cursor(&[split]).values(), [10, 13, 16]);
+    }
+}
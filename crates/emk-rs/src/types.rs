@@ -2,118 +2,665 @@ use flate2::read::ZlibDecoder;
 use md5::{Digest, Md5};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-const MAGIC: [u8; 4] = [0x53, 0x46, 0x44, 0x53];
+pub(crate) const MAGIC: [u8; 4] = [0x53, 0x46, 0x44, 0x53];
 
 use tracing::debug;
 type BoxedVec = Box<Vec<u8>>;
 
 pub fn string_kv_pair(data: String) -> Vec<(String, String)> {
     data.lines()
-        .filter(|s| !s.is_empty())
-        .map(|s| {
-            let mut split = s.split('=');
-            let key = split.next().unwrap().to_string();
-            let value = split.next().unwrap().to_string();
-            (key, value)
+        .filter_map(|s| {
+            let (key, value) = s.split_once('=')?;
+            Some((key.to_string(), value.to_string()))
         })
         .collect()
 }
+
+/// Key-value fields of a text tag, for looking up required fields
+struct KvFields {
+    tag: &'static str,
+    kv: std::collections::HashMap<String, String>,
+}
+
+impl KvFields {
+    fn new(tag: &'static str, data: &str) -> Self {
+        Self {
+            tag,
+            kv: string_kv_pair(data.to_string()).into_iter().collect(),
+        }
+    }
+
+    fn get(&self, field: &str) -> Result<String> {
+        self.kv
+            .get(field)
+            .cloned()
+            .ok_or_else(|| EmkError::MissingField {
+                tag: self.tag.to_string(),
+                field: field.to_string(),
+            })
+    }
+
+    fn parse<T: std::str::FromStr>(&self, field: &str) -> Result<T> {
+        let value = self.get(field)?;
+        value.trim().parse().map_err(|_| EmkError::InvalidField {
+            tag: self.tag.to_string(),
+            field: field.to_string(),
+            value,
+        })
+    }
+}
+/// Serializes key-value pairs back into the `KEY=VALUE\r\n` format used by text tags
+pub fn kv_string(pairs: &[(&str, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{key}={value}\r\n"))
+        .collect()
+}
+
+/// Original text of a `KEY=VALUE` tag. Saving the tag starts from it, so the lines of unknown keys
+/// and the values that were not changed keep their exact bytes, whatever their encoding.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawKv(Vec<u8>);
+
+impl RawKv {
+    /// Reads a tag as Windows-874 or UTF-8, see [`LyricEncoding::detect`]
+    fn decode(data: &[u8]) -> (Self, String) {
+        let text = LyricEncoding::detect(data).decode(data);
+        (Self(data.to_vec()), text)
+    }
+
+    /// Writes `pairs` over the original text, rewriting only the lines whose value changed and
+    /// appending the keys it lacks
+    fn update(&self, tag: &'static str, pairs: &[(&str, String)]) -> Vec<u8> {
+        let encoding = if self.0.is_ascii() {
+            // Both encodings read ASCII the same, so pick the one the new values need
+            let fits = pairs
+                .iter()
+                .all(|(_, value)| LyricEncoding::Windows874.encode(value).is_ok());
+            if fits {
+                LyricEncoding::Windows874
+            } else {
+                LyricEncoding::Utf8
+            }
+        } else {
+            LyricEncoding::detect(&self.0)
+        };
+        let original = KvFields::new(tag, &encoding.decode(&self.0));
+        // Numbers are written without the padding they may have been read with
+        let changed = |key: &str, value: &str| {
+            original
+                .get(key)
+                .map_or(true, |old| old != value && old.trim() != value)
+        };
+        let encode = |key: &str, value: &str| encoding.encode_lossy(&format!("{key}={value}"));
+
+        let mut out = Vec::with_capacity(self.0.len());
+        let mut written = vec![false; pairs.len()];
+        for line in self.0.split_inclusive(|&b| b == b'\n') {
+            // Split off the line break the same way as `str::lines`
+            let mut len = line.len() - usize::from(line.ends_with(b"\n"));
+            len -= usize::from(len > 0 && line[len - 1] == b'\r');
+            let (body, end) = line.split_at(len);
+
+            let text = encoding.decode(body);
+            let pair = text
+                .split_once('=')
+                .and_then(|(key, _)| pairs.iter().position(|(k, _)| *k == key));
+            match pair {
+                Some(i) if changed(pairs[i].0, &pairs[i].1) => {
+                    out.extend(encode(pairs[i].0, &pairs[i].1));
+                    written[i] = true;
+                }
+                Some(i) => {
+                    out.extend_from_slice(body);
+                    written[i] = true;
+                }
+                None => out.extend_from_slice(body),
+            }
+            out.extend_from_slice(end);
+        }
+
+        for ((key, value), _) in pairs.iter().zip(written).filter(|(_, written)| !written) {
+            if !out.is_empty() && !out.ends_with(b"\n") {
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend(encode(key, value));
+            out.extend_from_slice(b"\r\n");
+        }
+        out
+    }
+}
+
+/// Song code of a file imported from another format, its name without the extension
+fn file_code(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Preamble of a new file, as Extreme Karaoke writes it. The pointers to the tag table and its
+/// hash are left zeroed for [`EmkWriter`] to fill in, as are the 8 unknown bytes after the hash.
+pub(crate) const NEW_PREAMBLE: [u8; 101] = [
+    0x2e, 0x53, 0x46, 0x44, 0x53, 0x03, 0x09, 0xb1, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x53, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x06, 0x00, 0x06, 0x00, 0x06, 0x00, 0x02, 0x00, 0x06, 0x00, 0x02, 0x00, 0x06,
+    0x00, 0x06, 0x00, 0x02, 0x00,
+];
+
 #[derive(Debug)]
-pub struct EmkFile(pub Vec<Data>);
+pub struct EmkFile {
+    /// Tags in the order they appear in the tag table
+    pub tags: Vec<Data>,
+    /// Raw bytes preceding the first tag's data, including the file magic and header pointers
+    pub preamble: Vec<u8>,
+    /// Raw bytes following the tag table
+    pub trailer: Vec<u8>,
+}
 
 impl EmkFile {
-    pub fn from_reader(reader: EmkReader) -> Result<Self, String> {
+    /// Builds a new file from its tags, in the order they will be written
+    pub fn new(tags: Vec<Data>) -> Self {
+        Self {
+            tags,
+            preamble: NEW_PREAMBLE.to_vec(),
+            trailer: vec![0; 8],
+        }
+    }
+
+    pub fn from_reader(reader: EmkReader) -> Result<Self> {
         let data = reader.into_emk_file()?;
         Ok(data)
     }
 
-    pub fn read_from_path(path: &Path) -> Result<Self, String> {
-        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    pub fn read_from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)?;
         let reader = EmkReader::decrypt_default_key(&data)?;
         Self::from_reader(reader)
     }
 
-    pub fn try_read_from_path(path: &Path) -> Result<(Self, Vec<u8>), String> {
-        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    pub fn try_read_from_path(path: &Path) -> Result<(Self, Vec<u8>)> {
+        let data = std::fs::read(path)?;
         let (reader, key) = EmkReader::try_decrypt(&data)?;
         Ok((Self::from_reader(reader)?, key))
     }
 
-    pub fn try_from_bytes(data: &[u8]) -> Result<(Self, Vec<u8>), String> {
+    /// Opens a file with the first key of the ring that decrypts it, see
+    /// [`EmkFile::from_bytes_with_keyring`]
+    pub fn open_with_keyring(path: &Path, ring: &mut KeyRing) -> Result<(Self, Vec<u8>)> {
+        let data = std::fs::read(path)?;
+        Self::from_bytes_with_keyring(&data, ring)
+    }
+
+    /// Tries every key of the ring before cracking the key, adding a cracked key to the ring so
+    /// the next file with the same key opens right away
+    pub fn from_bytes_with_keyring(data: &[u8], ring: &mut KeyRing) -> Result<(Self, Vec<u8>)> {
+        if let Some(key) = ring.find_key(data) {
+            let key = key.to_vec();
+            return Ok((Self::from_bytes_with_key(data, &key)?, key));
+        }
+        let (file, key) = Self::try_from_bytes(data)?;
+        ring.add(&key);
+        Ok((file, key))
+    }
+
+    pub fn try_from_bytes(data: &[u8]) -> Result<(Self, Vec<u8>)> {
         let (reader, key) = EmkReader::try_decrypt(data)?;
         Ok((Self::from_reader(reader)?, key))
     }
 
-    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
         let reader = EmkReader::decrypt_default_key(data)?;
         Self::from_reader(reader)
     }
 
-    pub fn from_bytes_with_key(data: &[u8], key: &[u8]) -> Result<Self, String> {
+    pub fn from_bytes_with_key(data: &[u8], key: &[u8]) -> Result<Self> {
         let reader = EmkReader::decrypt(data, key)?;
         Self::from_reader(reader)
     }
 
-    pub fn from_bytes_decrypted(data: &[u8]) -> Result<Self, String> {
-        let reader = EmkReader::new(data.to_vec())?;
+    pub fn from_bytes_decrypted(data: &[u8]) -> Result<Self> {
+        let reader = EmkReader::from_decrypted(data)?;
         Self::from_reader(reader)
     }
 
+    /// Builds a file from the MIDI, lyric and cursor files of an NCN Karaoke song, deriving
+    /// `SONG_INFO` from the lyric file header. The song code is the name of the MIDI file.
+    pub fn from_ncn(midi: &Path, lyrics: &Path, cursor: &Path) -> Result<Self> {
+        Self::from_ncn_bytes(
+            &file_code(midi),
+            std::fs::read(midi)?,
+            std::fs::read(lyrics)?,
+            std::fs::read(cursor)?,
+        )
+    }
+
+    /// [`EmkFile::from_ncn`], from the contents of the files
+    pub fn from_ncn_bytes(
+        code: &str,
+        midi: Vec<u8>,
+        lyrics: Vec<u8>,
+        cursor: Vec<u8>,
+    ) -> Result<Self> {
+        let decoded = Lyrics::decode(&lyrics);
+        Cursor::parse(&cursor).validate(&decoded)?;
+        let song_info = ncn::song_info(code, &decoded, &TimeBase::from_midi(&midi)?);
+
+        let header = Header {
+            signature: "EMK".to_string(),
+            version: "2".to_string(),
+            raw: RawKv::default(),
+        };
+        Ok(Self::new(vec![
+            Data::new("HEADER", TagData::Header(header)),
+            Data::new("SONG_INFO", TagData::SongInfo(Box::new(song_info))),
+            Data::new("MIDI_DATA", TagData::Midi(Box::new(midi))),
+            Data::new("LYRIC_DATA", TagData::Lyrics(Box::new(lyrics))),
+            Data::new("CURSOR_DATA", TagData::Cursor(Box::new(cursor))),
+        ]))
+    }
+
+    /// Builds a file from a `.kar` file or a MIDI file with lyric events, see [`kar::read_kar`].
+    /// The song code is the name of the file.
+    pub fn from_kar(path: &Path) -> Result<Self> {
+        Self::from_kar_bytes(&file_code(path), std::fs::read(path)?)
+    }
+
+    pub fn from_kar_bytes(code: &str, data: Vec<u8>) -> Result<Self> {
+        let kar = kar::read_kar(&data)?;
+        let time_base = TimeBase::from_midi(&data)?;
+        let lines = timed::map_times(&kar.lines, |tick| time_base.ticks_to_cursor(tick));
+        // The lyrics go to LYRIC_DATA, so exporting the song again does not repeat them
+        let midi = kar::strip_kar(&data)?;
+        Self::from_timed_lines(code, midi, &kar.title, &kar.artist, &lines)
+    }
+
+    /// Builds a file from a MIDI file and its lyrics in LRC or enhanced LRC. The song code is the
+    /// name of the MIDI file.
+    pub fn from_midi_lrc(midi: &Path, lrc: &Path) -> Result<Self> {
+        let lrc = std::fs::read(lrc)?;
+        // LRC files of Thai songs are often in Windows-874
+        let lrc = LyricEncoding::detect(&lrc).decode(&lrc);
+        Self::from_midi_lrc_bytes(&file_code(midi), std::fs::read(midi)?, &lrc)
+    }
+
+    pub fn from_midi_lrc_bytes(code: &str, midi: Vec<u8>, lrc: &str) -> Result<Self> {
+        let lrc = Lrc::parse(lrc);
+        let time_base = TimeBase::from_midi(&midi)?;
+        let lines = timed::map_times(&lrc.lines, |ms| time_base.ms_to_cursor(ms as f64))
+            .iter()
+            .map(|line| match line.syllables.len() {
+                // Without word timing, the highlight moves through the line at a steady pace
+                1 => timed::split_cells(line),
+                _ => line.clone(),
+            })
+            .collect::<Vec<_>>();
+        let title = lrc.title.unwrap_or_default();
+        let artist = lrc.artist.unwrap_or_default();
+        Self::from_timed_lines(code, midi, &title, &artist, &lines)
+    }
+
+    /// Builds a file from lyric lines timed in cursor units
+    fn from_timed_lines(
+        code: &str,
+        midi: Vec<u8>,
+        title: &str,
+        artist: &str,
+        lines: &[TimedLine],
+    ) -> Result<Self> {
+        let text = lines.iter().map(TimedLine::text).collect();
+        let lyrics = Lyrics::new(title, artist, text).to_bytes()?;
+        let cursor = timed::to_cursor(lines).to_bytes();
+        Self::from_ncn_bytes(code, midi, lyrics, cursor)
+    }
+
     pub fn get_data(&self, tag: &str) -> Option<&Data> {
-        self.0.iter().find(|data| data.tag == tag)
+        self.tags.iter().find(|data| data.tag() == tag)
+    }
+
+    pub fn get_data_mut(&mut self, tag: &str) -> Option<&mut Data> {
+        self.tags.iter_mut().find(|data| data.tag() == tag)
+    }
+
+    /// Serializes and encrypts the file with the default key
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        EmkWriter::default_key().write(self)
+    }
+
+    pub fn to_bytes_with_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        EmkWriter::new(key).write(self)
+    }
+
+    /// Serializes the file without encrypting it
+    pub fn to_bytes_decrypted(&self) -> Result<Vec<u8>> {
+        EmkWriter::write_decrypted(self)
+    }
+
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        let data = self.to_bytes()?;
+        Ok(std::fs::write(path, data)?)
+    }
+
+    /// Writes the MIDI, lyric and cursor files of the song into an NCN Karaoke library, named
+    /// after [`SongInfo::code`]
+    pub fn export_ncn(&self, dir: &Path) -> Result<NcnPaths> {
+        let paths = NcnPaths::new(dir, self.song_info()?.code.trim())?;
+        for (tag, path) in [
+            ("MIDI_DATA", &paths.midi),
+            ("LYRIC_DATA", &paths.lyrics),
+            ("CURSOR_DATA", &paths.cursor),
+        ] {
+            let data = self
+                .get_data(tag)
+                .ok_or_else(|| EmkError::MissingTag(tag.to_string()))?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, data.data.to_bytes())?;
+        }
+        Ok(paths)
+    }
+
+    /// The decoded `SONG_INFO` tag
+    pub fn song_info(&self) -> Result<&SongInfo> {
+        match self.get_data("SONG_INFO").map(|d| &d.data) {
+            Some(TagData::SongInfo(s)) => Ok(s),
+            _ => Err(EmkError::MissingTag("SONG_INFO".to_string())),
+        }
+    }
+
+    pub fn song_info_mut(&mut self) -> Result<&mut SongInfo> {
+        match self.get_data_mut("SONG_INFO").map(|d| &mut d.data) {
+            Some(TagData::SongInfo(s)) => Ok(s),
+            _ => Err(EmkError::MissingTag("SONG_INFO".to_string())),
+        }
+    }
+
+    /// Decodes the lyrics of the song
+    pub fn lyrics(&self) -> Result<Lyrics> {
+        match self.get_data("LYRIC_DATA").map(|d| &d.data) {
+            Some(TagData::Lyrics(data)) => Ok(Lyrics::decode(data)),
+            _ => Err(EmkError::MissingTag("LYRIC_DATA".to_string())),
+        }
+    }
+
+    /// Decodes the lyric cursor of the song
+    pub fn cursor(&self) -> Result<Cursor> {
+        match self.get_data("CURSOR_DATA").map(|d| &d.data) {
+            Some(TagData::Cursor(data)) => Ok(Cursor::parse(data)),
+            _ => Err(EmkError::MissingTag("CURSOR_DATA".to_string())),
+        }
+    }
+
+    /// Pairs the lyrics with the cursor, giving the timing of every syllable in cursor units
+    pub fn timed_lyrics(&self) -> Result<Vec<TimedLine>> {
+        timed::align(&self.lyrics()?, &self.cursor()?)
+    }
+
+    /// Parses the MIDI data
+    pub fn midi(&self) -> Result<MidiSong> {
+        match self.get_data("MIDI_DATA").map(|d| &d.data) {
+            Some(TagData::Midi(data)) => MidiSong::parse(data),
+            _ => Err(EmkError::MissingTag("MIDI_DATA".to_string())),
+        }
+    }
+
+    /// Replaces the MIDI data with a serialized song
+    pub fn set_midi(&mut self, song: &MidiSong) -> Result<()> {
+        match self.get_data_mut("MIDI_DATA").map(|d| &mut d.data) {
+            Some(TagData::Midi(data)) => {
+                **data = song.to_bytes();
+                Ok(())
+            }
+            _ => Err(EmkError::MissingTag("MIDI_DATA".to_string())),
+        }
+    }
+
+    /// Transposes the song by `semitones`, leaving the drums alone, and renames the key in
+    /// `SONG_INFO` and the lyrics header to match. Keys that cannot be parsed are kept as they are.
+    pub fn transpose(&mut self, semitones: i32) -> Result<()> {
+        let transpose_key = |key: &str| {
+            key.parse::<Key>()
+                .ok()
+                .map(|parsed| parsed.transpose(semitones).to_string())
+        };
+        // Build every new payload before touching the file, so a failure leaves it unchanged
+        let mut song = self.midi()?;
+        song.transpose(semitones);
+        let song_key = self
+            .song_info()
+            .ok()
+            .and_then(|song_info| transpose_key(&song_info.key));
+        let lyrics = match self.get_data("LYRIC_DATA").map(|d| &d.data) {
+            Some(TagData::Lyrics(data)) => match transpose_key(&Lyrics::decode(data).key) {
+                Some(key) => Lyrics::replace_key(data, &key)?,
+                None => None,
+            },
+            _ => None,
+        };
+
+        self.set_midi(&song)?;
+        if let (Some(key), Ok(song_info)) = (song_key, self.song_info_mut()) {
+            song_info.key = key;
+        }
+        if let (Some(lyrics), Some(TagData::Lyrics(data))) =
+            (lyrics, self.get_data_mut("LYRIC_DATA").map(|d| &mut d.data))
+        {
+            **data = lyrics;
+        }
+        Ok(())
+    }
+
+    /// Plays the song `factor` times as fast, so 0.8 slows it down to 80%.
+    ///
+    /// The cursor counts quarter notes rather than time, so it follows the new tempo by itself.
+    /// The tempo of `SONG_INFO` is scaled to match, and so are its start and stop times on the
+    /// unverified assumption that they are clock times, see [`SongInfo::start_time`].
+    pub fn scale_tempo(&mut self, factor: f64) -> Result<()> {
+        let mut song = self.midi()?;
+        song.scale_tempo(factor)?;
+        self.set_midi(&song)?;
+
+        if let Ok(song_info) = self.song_info_mut() {
+            song_info.tempo = (song_info.tempo as f64 * factor).round() as u32;
+            song_info.start_time = (song_info.start_time as f64 / factor).round() as u32;
+            song_info.stop_time = (song_info.stop_time as f64 / factor).round() as u32;
+        }
+        Ok(())
+    }
+
+    /// Index of the MIDI channel with the guide melody, from 0 to 15. `VOCAL_CHANNEL` counts
+    /// channels from 1.
+    pub fn vocal_channel(&self) -> Result<u8> {
+        let channel = self.song_info()?.vocal_channel;
+        match channel {
+            1..=16 => Ok(channel - 1),
+            _ => Err(EmkError::InvalidField {
+                tag: "SONG_INFO".to_string(),
+                field: "VOCAL_CHANNEL".to_string(),
+                value: channel.to_string(),
+            }),
+        }
+    }
+
+    /// Scales the note velocities of a MIDI channel, from 0 to 15, see
+    /// [`MidiSong::set_channel_volume`]
+    pub fn set_channel_volume(&mut self, channel: u8, volume: f64) -> Result<()> {
+        let mut song = self.midi()?;
+        song.set_channel_volume(channel, volume)?;
+        self.set_midi(&song)
+    }
+
+    /// Silences a MIDI channel, from 0 to 15
+    pub fn mute_channel(&mut self, channel: u8) -> Result<()> {
+        self.set_channel_volume(channel, 0.0)
+    }
+
+    /// Silences every MIDI channel but `channel`
+    pub fn solo_channel(&mut self, channel: u8) -> Result<()> {
+        let mut song = self.midi()?;
+        song.solo_channel(channel)?;
+        self.set_midi(&song)
+    }
+
+    /// Scales the note velocities of the guide melody, so 0.5 makes it half as loud
+    pub fn set_vocal_volume(&mut self, volume: f64) -> Result<()> {
+        self.set_channel_volume(self.vocal_channel()?, volume)
+    }
+
+    /// Silences the guide melody, leaving a minus-one backing track
+    pub fn mute_vocals(&mut self) -> Result<()> {
+        self.mute_channel(self.vocal_channel()?)
+    }
+
+    /// Silences everything but the guide melody, for practicing it
+    pub fn solo_vocals(&mut self) -> Result<()> {
+        self.solo_channel(self.vocal_channel()?)
+    }
+
+    /// Renders the song to interleaved stereo samples with the presets of a SoundFont, from
+    /// `START_TIME` to `STOP_TIME` of `SONG_INFO` read as milliseconds, a unit no sample file
+    /// confirms yet (see [`SongInfo::start_time`]). A `STOP_TIME` of 0 plays the song to the end.
+    #[cfg(feature = "synth")]
+    pub fn render_pcm(&self, font: &SoundFont, options: &SynthOptions) -> Result<Vec<f32>> {
+        let (start, stop) = match self.song_info() {
+            Ok(song) => (
+                song.start_time,
+                Some(song.stop_time).filter(|&stop| stop > song.start_time),
+            ),
+            Err(_) => (0, None),
+        };
+        synth::render(&self.midi()?, font, options, start, stop)
+    }
+
+    /// Renders the song like [`EmkFile::render_pcm`] as a 16-bit WAV file
+    #[cfg(feature = "synth")]
+    pub fn render_wav(&self, font: &SoundFont, options: &SynthOptions) -> Result<Vec<u8>> {
+        let samples = self.render_pcm(font, options)?;
+        synth::to_wav(&samples, options.sample_rate)
+    }
+
+    /// Reads the PPQ and tempo map of the MIDI data
+    pub fn time_base(&self) -> Result<TimeBase> {
+        Ok(TimeBase::from_song(&self.midi()?))
+    }
+
+    /// [`EmkFile::timed_lyrics`], with the timings in milliseconds
+    pub fn timed_lyrics_ms(&self) -> Result<Vec<TimedLine>> {
+        Ok(self.time_base()?.lines_to_ms(&self.timed_lyrics()?))
+    }
+
+    /// Exports the lyrics as line-level LRC
+    pub fn to_lrc(&self) -> Result<String> {
+        Ok(lrc::to_lrc(&self.timed_lyrics_ms()?, self.song_info().ok()))
+    }
+
+    /// Exports the lyrics as enhanced LRC, with word-level timing
+    pub fn to_enhanced_lrc(&self) -> Result<String> {
+        Ok(lrc::to_enhanced_lrc(
+            &self.timed_lyrics_ms()?,
+            self.song_info().ok(),
+        ))
+    }
+
+    /// Exports the lyrics as an Advanced SubStation Alpha karaoke script
+    pub fn to_ass(&self, options: &AssOptions) -> Result<String> {
+        Ok(ass::to_ass(
+            &self.timed_lyrics_ms()?,
+            self.song_info().ok(),
+            options,
+        ))
+    }
+
+    /// Exports the lyrics as SubRip subtitles, one cue per line
+    pub fn to_srt(&self) -> Result<String> {
+        Ok(srt::to_srt(&self.timed_lyrics_ms()?))
+    }
+
+    /// Exports the lyrics as WebVTT subtitles, with inline timestamps for every syllable
+    pub fn to_webvtt(&self) -> Result<String> {
+        Ok(vtt::to_webvtt(
+            &self.timed_lyrics_ms()?,
+            self.song_info().ok(),
+        ))
+    }
+
+    /// Exports the song as a Soft Karaoke `.kar` file: the MIDI data with a lyrics track timed by
+    /// the cursor
+    pub fn to_kar(&self) -> Result<Vec<u8>> {
+        self.to_kar_with_options(&KarOptions::default())
+    }
+
+    pub fn to_kar_with_options(&self, options: &KarOptions) -> Result<Vec<u8>> {
+        let midi = match self.get_data("MIDI_DATA").map(|d| &d.data) {
+            Some(TagData::Midi(data)) => data,
+            _ => return Err(EmkError::MissingTag("MIDI_DATA".to_string())),
+        };
+        let lyrics = self.lyrics()?;
+        let lines = timed::align(&lyrics, &self.cursor()?)?;
+        let lines = TimeBase::from_midi(midi)?.lines_to_ticks(&lines);
+        kar::to_kar(
+            midi,
+            &lines,
+            self.song_info().ok(),
+            lyrics.encoding,
+            options,
+        )
+    }
+
+    /// Checks every tag against the MD5 hash and size it was read with.
+    ///
+    /// A tag that was edited after reading shows up as a mismatch. To audit a file on disk,
+    /// including tags that cannot be inflated, use [`EmkReader::verify`] instead.
+    pub fn verify(&self) -> IntegrityReport {
+        verify_file(self)
     }
 }
 
 // #[derive(Debug)]
 pub struct Data {
-    /// ID of the tag
-    pub tag: String,
+    /// Entry of the tag in the tag table, as it was read
+    pub entry: TagEntry,
 
-    /// Beginning offset of compressed data
-    pub data_begin: u64,
-    /// End offset of compressed data
-    pub data_end: u64,
-    /// MD5 hash of the compressed data
-    pub md5_hash: [u8; 16],
-    /// Uncompressed size of the data
-    pub uncompressed_size: u64,
+    pub data: TagData,
+}
 
-    // unknown fields
-    pub unk2: bool,
-    pub unk5: bool,
-    pub unk6: bool,
-    pub unk7: String,
-    pub unk8: bool,
+impl Data {
+    /// A new tag, with the table entry fields Extreme Karaoke writes
+    pub fn new(tag: &str, data: TagData) -> Self {
+        Self {
+            entry: TagEntry::new(tag),
+            data,
+        }
+    }
 
-    pub data: TagData,
+    /// ID of the tag
+    pub fn tag(&self) -> &str {
+        &self.entry.tag
+    }
 }
 
 impl std::fmt::Debug for Data {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (lyrics, midi);
         f.debug_struct("Data")
-            .field("tag", &self.tag)
-            .field("data_begin", &self.data_begin)
-            .field("data_end", &self.data_end)
-            .field("md5_hash", &hex::encode(self.md5_hash))
-            .field("uncompressed_size", &self.uncompressed_size)
-            .field("unk2", &self.unk2)
-            .field("unk5", &self.unk5)
-            .field("unk6", &self.unk6)
-            .field("unk7", &self.unk7)
-            .field("unk8", &self.unk8)
+            .field("entry", &self.entry)
             .field(
                 "data",
                 match &self.data {
                     TagData::Header(h) => h,
-                    TagData::Midi(_) => &"<MIDI>",
-                    TagData::Lyrics(l) => {
-                        let lyrics = String::from_utf8_lossy(l);
-                        {
-                            {
-                                let formatted_lyrics = format!("{:?}", lyrics);
-                                &*Box::leak(Box::new(formatted_lyrics))
-                            }
+                    TagData::Midi(data) => match MidiSong::parse(data) {
+                        Ok(song) => {
+                            midi = song;
+                            &midi
                         }
+                        Err(_) => &"<MIDI>",
+                    },
+                    TagData::Lyrics(l) => {
+                        lyrics = Lyrics::decode(l).to_text();
+                        &lyrics
                     }
                     TagData::Cursor(_) => &"<Cursor>",
                     TagData::SongInfo(s) => s,
@@ -135,52 +682,76 @@ pub enum TagData {
 }
 
 impl TagData {
-    fn from_buf_with_tag(tag: &str, data: Vec<u8>) -> Self {
-        match tag {
-            "HEADER" => {
-                let header_str = String::from_utf8_lossy(&data);
-                TagData::Header(Header::from_kv(&header_str.to_string()))
-            }
-            "SONG_INFO" => {
-                let song_info_str = String::from_utf8_lossy(&data);
-                TagData::SongInfo(Box::new(SongInfo::from_kv(&song_info_str.to_string())))
+    /// Serializes the tag back into its uncompressed payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            TagData::Header(h) => h.to_kv_bytes(),
+            TagData::SongInfo(s) => s.to_kv_bytes(),
+            TagData::Midi(d) | TagData::Lyrics(d) | TagData::Cursor(d) | TagData::Unknown(d) => {
+                d.to_vec()
             }
+        }
+    }
+
+    pub(crate) fn from_buf_with_tag(tag: &str, data: Vec<u8>) -> Result<Self> {
+        Ok(match tag {
+            "HEADER" => TagData::Header(Header::from_kv_bytes(&data)?),
+            "SONG_INFO" => TagData::SongInfo(Box::new(SongInfo::from_kv_bytes(&data)?)),
             "MIDI_DATA" => TagData::Midi(Box::new(data)),
             "LYRIC_DATA" => TagData::Lyrics(Box::new(data)),
             "CURSOR_DATA" => TagData::Cursor(Box::new(data)),
             _ => TagData::Unknown(Box::new(data)),
-        }
+        })
     }
-    pub fn from_reader(reader: &mut EmkReader) -> Result<Vec<Self>, String> {
+    pub fn from_reader(reader: &EmkReader) -> Result<Vec<Self>> {
         reader
-            .read_tags()
+            .entries()
             .iter()
-            .map(|tag| {
-                let tag_name = tag.get("tag").unwrap().to_string();
-
-                let raw_data = reader
-                    .read_tag_data(&tag_name)
-                    .ok_or("Failed to read tag data")?;
-                Ok(TagData::from_buf_with_tag(&tag_name, raw_data))
-            })
-            .collect::<Result<Vec<_>, _>>()
+            .map(|entry| TagData::from_buf_with_tag(&entry.tag, reader.inflate(entry)?))
+            .collect::<Result<Vec<_>>>()
     }
 }
 #[derive(Debug)]
 pub struct Header {
     pub signature: String,
     pub version: String,
+    /// Text the header was read from, see [`RawKv`]
+    pub raw: RawKv,
 }
 
 impl Header {
-    pub fn from_kv(data: &String) -> Self {
-        let kv = string_kv_pair(data.to_string())
-            .into_iter()
-            .collect::<std::collections::HashMap<String, String>>();
-        Self {
-            signature: kv.get("SIGNATURE").unwrap().to_string(),
-            version: kv.get("VERSION").unwrap().to_string(),
-        }
+    pub fn from_kv(data: &str) -> Result<Self> {
+        let kv = KvFields::new("HEADER", data);
+        Ok(Self {
+            signature: kv.get("SIGNATURE")?,
+            version: kv.get("VERSION")?,
+            raw: RawKv::default(),
+        })
+    }
+
+    /// Reads the header from the bytes of the tag, keeping them for [`Header::to_kv_bytes`]
+    pub fn from_kv_bytes(data: &[u8]) -> Result<Self> {
+        let (raw, text) = RawKv::decode(data);
+        Ok(Self {
+            raw,
+            ..Self::from_kv(&text)?
+        })
+    }
+
+    fn kv_pairs(&self) -> [(&'static str, String); 2] {
+        [
+            ("SIGNATURE", self.signature.clone()),
+            ("VERSION", self.version.clone()),
+        ]
+    }
+
+    pub fn to_kv(&self) -> String {
+        kv_string(&self.kv_pairs())
+    }
+
+    /// Serializes the header over the text it was read from, see [`RawKv`]
+    pub fn to_kv_bytes(&self) -> Vec<u8> {
+        self.raw.update("HEADER", &self.kv_pairs())
     }
 }
 #[derive(Debug)]
@@ -201,40 +772,80 @@ pub struct SongInfo {
     pub artist: String,
     /// Language
     pub language: String,
-    /// MIDI channel with the vocals
+    /// MIDI channel with the guide melody, counting from 1, see [`EmkFile::vocal_channel`]
     pub vocal_channel: u8,
     /// Original file name
     pub file_name: String,
     /// Lyric title
     pub lyric_title: String,
-    /// Start time of the song
+    /// Start time of the song. Taken to be in milliseconds, which is unverified: every known
+    /// file has 0 here.
     pub start_time: u32,
-    /// End time of the song
+    /// End time of the song, in the same unverified unit as `start_time`. 0 plays the MIDI data
+    /// to the end.
     pub stop_time: u32,
-    /// Tempo of the song
+    /// Tempo of the song in BPM, see [`EmkFile::time_base`] for the full tempo map
     pub tempo: u32,
+    /// Text the song info was read from, including the keys not listed above, see [`RawKv`]
+    pub raw: RawKv,
 }
 
 impl SongInfo {
-    pub fn from_kv(data: &String) -> Self {
-        let kv = string_kv_pair(data.to_string())
-            .into_iter()
-            .collect::<std::collections::HashMap<String, String>>();
-        Self {
-            code: kv.get("CODE").unwrap().to_string(),
-            song_type: kv.get("TYPE").unwrap().to_string(),
-            subtitle_type: kv.get("SUB_TYPE").unwrap().to_string(),
-            title: kv.get("TITLE").unwrap().to_string(),
-            key: kv.get("KEY").unwrap().to_string(),
-            artist: kv.get("ARTIST").unwrap().to_string(),
-            language: kv.get("LANGUAGE").unwrap().to_string(),
-            vocal_channel: kv.get("VOCAL_CHANNEL").unwrap().parse::<u8>().unwrap(),
-            file_name: kv.get("FILE_NAME").unwrap().to_string(),
-            lyric_title: kv.get("LYRIC_TITLE").unwrap().to_string(),
-            start_time: kv.get("START_TIME").unwrap().parse::<u32>().unwrap(),
-            stop_time: kv.get("STOP_TIME").unwrap().parse::<u32>().unwrap(),
-            tempo: kv.get("TEMPO").unwrap().parse::<u32>().unwrap(),
-        }
+    pub fn from_kv(data: &str) -> Result<Self> {
+        let kv = KvFields::new("SONG_INFO", data);
+        Ok(Self {
+            code: kv.get("CODE")?,
+            song_type: kv.get("TYPE")?,
+            subtitle_type: kv.get("SUB_TYPE")?,
+            title: kv.get("TITLE")?,
+            key: kv.get("KEY")?,
+            artist: kv.get("ARTIST")?,
+            language: kv.get("LANGUAGE")?,
+            vocal_channel: kv.parse("VOCAL_CHANNEL")?,
+            file_name: kv.get("FILE_NAME")?,
+            lyric_title: kv.get("LYRIC_TITLE")?,
+            start_time: kv.parse("START_TIME")?,
+            stop_time: kv.parse("STOP_TIME")?,
+            tempo: kv.parse("TEMPO")?,
+            raw: RawKv::default(),
+        })
+    }
+
+    /// Reads the song info from the bytes of the tag, keeping them for
+    /// [`SongInfo::to_kv_bytes`]
+    pub fn from_kv_bytes(data: &[u8]) -> Result<Self> {
+        let (raw, text) = RawKv::decode(data);
+        Ok(Self {
+            raw,
+            ..Self::from_kv(&text)?
+        })
+    }
+
+    fn kv_pairs(&self) -> [(&'static str, String); 13] {
+        [
+            ("CODE", self.code.clone()),
+            ("TYPE", self.song_type.clone()),
+            ("SUB_TYPE", self.subtitle_type.clone()),
+            ("TITLE", self.title.clone()),
+            ("KEY", self.key.clone()),
+            ("ARTIST", self.artist.clone()),
+            ("LANGUAGE", self.language.clone()),
+            ("VOCAL_CHANNEL", self.vocal_channel.to_string()),
+            ("FILE_NAME", self.file_name.clone()),
+            ("LYRIC_TITLE", self.lyric_title.clone()),
+            ("START_TIME", self.start_time.to_string()),
+            ("STOP_TIME", self.stop_time.to_string()),
+            ("TEMPO", self.tempo.to_string()),
+        ]
+    }
+
+    pub fn to_kv(&self) -> String {
+        kv_string(&self.kv_pairs())
+    }
+
+    /// Serializes the song info over the text it was read from, see [`RawKv`]
+    pub fn to_kv_bytes(&self) -> Vec<u8> {
+        self.raw.update("SONG_INFO", &self.kv_pairs())
     }
 }
 
@@ -255,9 +866,30 @@ pub enum DataTypeOut {
     Data(Vec<u8>),
 }
 
-use std::{fmt, io::Read, path::Path};
-
-use crate::util::{xor, xor_cracker_alula, EMK_MAGIC};
+use std::{borrow::Cow, fmt, io::Read, path::Path};
+
+use crate::ass::{self, AssOptions};
+use crate::cursor::Cursor;
+use crate::error::{EmkError, Result};
+use crate::integrity::{verify_file, verify_reader, IntegrityReport};
+use crate::kar::{self, KarOptions};
+use crate::key::Key;
+use crate::keyring::KeyRing;
+use crate::lrc::{self, Lrc};
+use crate::lyrics::{LyricEncoding, Lyrics};
+use crate::midi::MidiSong;
+use crate::ncn::{self, NcnPaths};
+use crate::srt;
+use crate::stream::EmkStreamReader;
+#[cfg(feature = "synth")]
+use crate::synth::{self, SoundFont, SynthOptions};
+use crate::timebase::TimeBase;
+use crate::timed::{self, TimedLine};
+use crate::util::{
+    xor, xor_cracker_alula_with_options, xor_cracker_known_plaintext, CrackOptions, EMK_MAGIC,
+};
+use crate::vtt;
+use crate::writer::EmkWriter;
 
 impl fmt::Display for DataTypeOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -295,289 +927,426 @@ impl From<DataTypeOut> for Vec<u8> {
     }
 }
 
-impl From<DataTypeOut> for u8 {
-    fn from(val: DataTypeOut) -> Self {
-        match val {
-            DataTypeOut::Byte(b) => b,
-            DataTypeOut::Short(s) => s as u8,
-            DataTypeOut::Int(i) => i as u8,
-            DataTypeOut::String(s) => s.parse::<u8>().unwrap(),
-            DataTypeOut::Data(d) => d[0],
+macro_rules! impl_try_from_data_type_out {
+    ($($t:ty),*) => {$(
+        impl TryFrom<DataTypeOut> for $t {
+            type Error = EmkError;
+
+            fn try_from(val: DataTypeOut) -> Result<Self> {
+                let converted = match &val {
+                    DataTypeOut::Byte(b) => <$t>::try_from(*b).ok(),
+                    DataTypeOut::Short(s) => <$t>::try_from(*s).ok(),
+                    DataTypeOut::Int(i) => <$t>::try_from(*i).ok(),
+                    DataTypeOut::String(s) => s.parse::<$t>().ok(),
+                    DataTypeOut::Data(d) => d
+                        .get(..std::mem::size_of::<$t>())
+                        .map(|b| <$t>::from_le_bytes(b.try_into().unwrap())),
+                };
+                converted.ok_or_else(|| EmkError::InvalidConversion {
+                    value: val.to_string(),
+                    target: stringify!($t),
+                })
+            }
         }
-    }
+    )*};
 }
 
-impl From<DataTypeOut> for u16 {
-    fn from(val: DataTypeOut) -> Self {
-        match val {
-            DataTypeOut::Byte(b) => b as u16,
-            DataTypeOut::Short(s) => s,
-            DataTypeOut::Int(i) => i as u16,
-            DataTypeOut::String(s) => s.parse::<u16>().unwrap(),
-            DataTypeOut::Data(d) => u16::from_le_bytes(d[0..2].try_into().unwrap()),
-        }
-    }
+impl_try_from_data_type_out!(u8, u16, u32, u64);
+
+/// An entry of the tag table, describing where the compressed data of a tag is stored.
+///
+/// The fields whose meaning is not known yet are kept exactly as they were read, including their
+/// data type, so writing the entry back produces the same bytes.
+#[derive(Clone)]
+pub struct TagEntry {
+    /// ID of the tag
+    pub tag: String,
+    /// Uncompressed size of the data
+    pub uncompressed_size: u64,
+    /// Beginning offset of compressed data
+    pub data_begin: u64,
+    /// End offset of compressed data
+    pub data_end: u64,
+    /// MD5 hash of the uncompressed data
+    pub md5_hash: [u8; 16],
+
+    // unknown fields
+    /// Usually `Byte(0)`
+    pub unk2: DataTypeOut,
+    /// Usually `Byte(1)`
+    pub unk5: DataTypeOut,
+    /// Usually `Byte(0)`
+    pub unk6: DataTypeOut,
+    /// Usually an empty string
+    pub unk7: DataTypeOut,
+    /// Usually `Byte(0)`
+    pub unk8: DataTypeOut,
 }
 
-impl From<DataTypeOut> for u32 {
-    fn from(val: DataTypeOut) -> Self {
-        match val {
-            DataTypeOut::Byte(b) => b as u32,
-            DataTypeOut::Short(s) => s as u32,
-            DataTypeOut::Int(i) => i,
-            DataTypeOut::String(s) => s.parse::<u32>().unwrap(),
-            DataTypeOut::Data(d) => u32::from_le_bytes(d[0..4].try_into().unwrap()),
+impl TagEntry {
+    /// An entry for a new tag. The size, offsets and hash are filled in when the file is written.
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            uncompressed_size: 0,
+            data_begin: 0,
+            data_end: 0,
+            md5_hash: [0; 16],
+            unk2: DataTypeOut::Byte(0),
+            unk5: DataTypeOut::Byte(1),
+            unk6: DataTypeOut::Byte(0),
+            unk7: DataTypeOut::String(String::new()),
+            unk8: DataTypeOut::Byte(0),
         }
     }
+
+    /// The fields whose meaning is not known yet, by name
+    pub fn unknown_fields(&self) -> [(&'static str, &DataTypeOut); 5] {
+        [
+            ("unk2", &self.unk2),
+            ("unk5", &self.unk5),
+            ("unk6", &self.unk6),
+            ("unk7", &self.unk7),
+            ("unk8", &self.unk8),
+        ]
+    }
 }
 
-impl From<DataTypeOut> for u64 {
-    fn from(val: DataTypeOut) -> Self {
-        match val {
-            DataTypeOut::Byte(b) => b as u64,
-            DataTypeOut::Short(s) => s as u64,
-            DataTypeOut::Int(i) => i as u64,
-            DataTypeOut::String(s) => s.parse::<u64>().unwrap(),
-            DataTypeOut::Data(d) => u64::from_le_bytes(d[0..8].try_into().unwrap()),
-        }
+impl fmt::Debug for TagEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TagEntry")
+            .field("tag", &self.tag)
+            .field("data_begin", &self.data_begin)
+            .field("data_end", &self.data_end)
+            .field("md5_hash", &hex::encode(self.md5_hash))
+            .field("uncompressed_size", &self.uncompressed_size)
+            .field("unk2", &self.unk2)
+            .field("unk5", &self.unk5)
+            .field("unk6", &self.unk6)
+            .field("unk7", &self.unk7)
+            .field("unk8", &self.unk8)
+            .finish()
     }
 }
 
-pub struct EmkReader {
-    data: Vec<u8>,
-    header: Vec<u8>,
+/// Cursor over the decrypted tag table
+struct TableReader<'a> {
+    table: &'a [u8],
+    /// Absolute offset of the table in the decrypted file
+    base: usize,
     pos: usize,
+    /// Name of the tag entry being read, for error reporting
+    tag: Option<String>,
 }
 
-impl EmkReader {
-    pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Self, String> {
-        let data = xor(data, key)?;
-
-        let header_pos = u64::from_le_bytes(
-            data[0x22..0x2a]
-                .try_into()
-                .map_err(|_| "Invalid header position range")?,
-        ) as usize;
-        let header_end = u64::from_le_bytes(
-            data[0x2a..0x32]
-                .try_into()
-                .map_err(|_| "Invalid header end range")?,
-        ) as usize;
-
-        let header = data
-            .get(header_pos..header_end)
-            .ok_or("Invalid header range")?
-            .to_vec();
-
-        Ok(Self {
-            data: data.clone(),
-            header,
-            pos: 0,
-        })
-    }
-
-    /// Takes an already decrypted EMK file, and returns the reader
-    pub fn new(data: Vec<u8>) -> Result<Self, String> {
-        let header_pos = data
-            .get(0x22..0x2a)
-            .ok_or("Invalid header position range")?
-            .try_into()
-            .map(u64::from_le_bytes)
-            .map_err(|_| "Invalid header position range")? as usize;
-        let header_end = u64::from_le_bytes(
-            data[0x2a..0x32]
-                .try_into()
-                .map_err(|_| "Invalid header end range")?,
-        ) as usize;
-
-        let header = data
-            .get(header_pos..header_end)
-            .ok_or("Invalid header range")?
-            .to_vec();
-
-        Ok(Self {
-            data: data.clone(),
-            header,
+impl<'a> TableReader<'a> {
+    fn new(table: &'a [u8], base: usize) -> Self {
+        Self {
+            table,
+            base,
             pos: 0,
-        })
+            tag: None,
+        }
     }
 
-    pub fn decrypt_default_key(data: &[u8]) -> Result<Self, String> {
-        Self::decrypt(data, EMK_MAGIC.to_be_bytes().as_ref())
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.table.len()
     }
 
-    /// Attempt to crack the key using Alula's algorithm
-    pub fn try_decrypt(data: &[u8]) -> Result<(Self, Vec<u8>), String> {
-        // Try every possible u64
-        // use rayon::prelude::*;
-
-        let key = xor_cracker_alula(data).map_err(|e| e.to_string());
-        if let Ok(key) = key {
-            return Ok((Self::decrypt(data, &key)?, key));
-        }
-
-        Err("Failed to decrypt".to_string())
+    /// Absolute offset of the current position in the decrypted file
+    fn offset(&self) -> usize {
+        self.base + self.pos
     }
 
     fn check_magic(&mut self, magic: &[u8]) -> bool {
-        let data = self.header[self.pos..self.pos + magic.len()].to_vec();
-        if data != magic {
+        if self.table.get(self.pos..self.pos + magic.len()) != Some(magic) {
             return false;
         }
         // Oh yeah, we need to skip magic bytes
         self.pos += magic.len();
+        // A new entry starts here, forget the previous tag
+        self.tag = None;
         true
     }
 
-    #[allow(dead_code)]
-    fn skip(&mut self, n: usize) {
-        self.pos += n;
+    fn expect_magic(&mut self) -> Result<()> {
+        if !self.check_magic(MAGIC.as_ref()) {
+            return Err(EmkError::InvalidMagic {
+                tag: None,
+                offset: self.offset(),
+            });
+        }
+        Ok(())
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.header[self.pos];
-        self.pos += 1;
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.table.len());
+        let Some(end) = end else {
+            return Err(EmkError::Truncated {
+                tag: self.tag.clone(),
+                offset: self.offset(),
+            });
+        };
+        let bytes = &self.table[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
 
-        byte
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
     }
 
-    fn read_u16(&mut self) -> u16 {
-        let res = u16::from_le_bytes(self.header[self.pos..self.pos + 2].try_into().unwrap());
-        self.pos += 2;
-        res
+    fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let res = u32::from_le_bytes(self.header[self.pos..self.pos + 4].try_into().unwrap());
-        self.pos += 4;
-        res
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
     }
-    fn read_string(&mut self) -> String {
-        let len = self.read_byte() as usize;
-        let str = String::from_utf8(self.header[self.pos..self.pos + len].to_vec()).unwrap();
-        self.pos += len;
-        str
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_byte()? as usize;
+        let offset = self.offset();
+        let bytes = self.read_bytes(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| EmkError::InvalidUtf8 {
+            tag: self.tag.clone(),
+            offset,
+        })
     }
 
-    fn read_tag(&mut self) -> DataTypeOut {
-        let byte = self.read_byte();
+    /// Reads the embedded MD5 hash of an entry
+    fn read_md5(&mut self) -> Result<[u8; 16]> {
+        Ok(self.read_bytes(16)?.try_into().unwrap())
+    }
 
-        // debug!("Reading tag: {}", byte);
+    fn read_tag(&mut self) -> Result<DataTypeOut> {
+        let offset = self.offset();
+        let byte = self.read_byte()?;
 
         let tag: Option<DataType> = FromPrimitive::from_u8(byte);
-        match tag {
-            Some(DataType::Byte) => {
-                let v = self.read_byte();
-                // debug!("Byte: {}", v);
-                DataTypeOut::Byte(v)
-            }
-            Some(DataType::Short) => {
-                let v = self.read_u16();
-                // debug!("Short: {}", v);
-                DataTypeOut::Short(v)
-            }
-            Some(DataType::Int) => {
-                let v = self.read_u32();
-                // debug!("Int: {}", v);
-                DataTypeOut::Int(v)
+        let out = match tag {
+            Some(DataType::Byte) => DataTypeOut::Byte(self.read_byte()?),
+            Some(DataType::Short) => DataTypeOut::Short(self.read_u16()?),
+            Some(DataType::Int) => DataTypeOut::Int(self.read_u32()?),
+            Some(DataType::String) => DataTypeOut::String(self.read_string()?),
+            None => {
+                return Err(EmkError::UnknownDataType {
+                    tag: self.tag.clone(),
+                    offset,
+                    byte,
+                })
             }
-            Some(DataType::String) => {
-                let v = self.read_string();
-                // debug!("String: {}", v);
-                DataTypeOut::String(v)
+        };
+        Ok(out)
+    }
+
+    /// Reads the tag name of an entry, remembering it for error reporting
+    fn read_tag_name(&mut self) -> Result<String> {
+        let offset = self.offset();
+        match self.read_tag()? {
+            DataTypeOut::String(s) => {
+                self.tag = Some(s.clone());
+                Ok(s)
             }
+            _ => Err(EmkError::InvalidDataType {
+                tag: None,
+                offset,
+                field: "tag",
+            }),
+        }
+    }
 
-            None => todo!(),
+    /// Reads an integer field, accepting any of the integer data types
+    fn read_int(&mut self, field: &'static str) -> Result<u64> {
+        let offset = self.offset();
+        match self.read_tag()? {
+            DataTypeOut::Byte(b) => Ok(b as u64),
+            DataTypeOut::Short(s) => Ok(s as u64),
+            DataTypeOut::Int(i) => Ok(i as u64),
+            _ => Err(EmkError::InvalidDataType {
+                tag: self.tag.clone(),
+                offset,
+                field,
+            }),
         }
     }
 
-    pub fn read_header(&mut self) -> Result<(), String> {
-        // self.decrypt();
-        while self.pos < self.header.len() {
-            if !self.check_magic(MAGIC.as_ref()) {
-                return Err("Magic check failed".to_string());
-            }
-            let tag = self.read_tag();
-            let uncompressed_size = self.read_tag();
-            let _unk2 = self.read_tag();
-            let data_begin = std::convert::Into::<u16>::into(self.read_tag());
-            let data_end = std::convert::Into::<u16>::into(self.read_tag());
-            let _unk5 = self.read_tag();
-            let _unk6 = self.read_tag();
-            let md5_hash = {
-                let res = self.data[self.pos..self.pos + 16].to_vec();
-                self.pos += 16;
-                res
-            };
-            let _unk7 = self.read_tag();
-            let _unk8 = self.read_tag();
-
-            debug!(
-                "=== Header ===\nTag: {:?}\nUncompressed size: {:?}\nUnk2: {:?}\nData begin: {:?}\nData end: {:?}\nUnk5: {:?}\nUnk6: {:?}\nMD5 hash: {:?}\nUnk7: {:?}\nUnk8: {:?}",
-                tag, uncompressed_size, _unk2, data_begin, data_end, _unk5, _unk6, md5_hash, _unk7, _unk8
-            );
+    /// Reads a complete entry, starting at its magic
+    fn read_entry(&mut self) -> Result<TagEntry> {
+        self.expect_magic()?;
+        Ok(TagEntry {
+            tag: self.read_tag_name()?,
+            uncompressed_size: self.read_int("uncompressed_size")?,
+            unk2: self.read_tag()?,
+            data_begin: self.read_int("data_begin")?,
+            data_end: self.read_int("data_end")?,
+            unk5: self.read_tag()?,
+            unk6: self.read_tag()?,
+            md5_hash: self.read_md5()?,
+            unk7: self.read_tag()?,
+            unk8: self.read_tag()?,
+        })
+    }
+}
 
-            // compressed data
-            let mut hasher = Md5::new();
-            let compressed_data = self.data[data_begin as usize..data_end as usize].to_vec();
-
-            // check md5 hash
-
-            // ? md5 hash is wrong for some reason
-            let raw_data = {
-                let mut buf = Vec::new();
-                let mut decoder = ZlibDecoder::new(compressed_data.as_slice());
-                decoder.read_to_end(&mut buf).map_err(|e| e.to_string())?;
-                buf
-            };
-            hasher.update(raw_data.as_slice());
-            let hash = hasher.finalize_reset();
-            debug!("Hash: {:?}", hash);
-            debug!("Embedded Hash: {:?}", md5_hash);
-
-            if let DataTypeOut::String(s) = tag {
-                if let "HEADER" = s.as_str() {
-                    debug!("--- HEADER ---");
-                    debug!(
-                        "{}",
-                        String::from_utf8(raw_data).map_err(|e| e.to_string())?
-                    );
-                    debug!("--- END HEADER ---");
-                }
+/// Parses every entry of a decrypted tag table located at `base` in the file
+pub(crate) fn read_entries(table: &[u8], base: usize) -> Result<Vec<TagEntry>> {
+    let mut reader = TableReader::new(table, base);
+    let mut entries = Vec::new();
+    while !reader.is_at_end() {
+        entries.push(reader.read_entry()?);
+    }
+    Ok(entries)
+}
+
+/// Reads an EMK archive from a decrypted buffer.
+///
+/// The tag table is parsed once when the reader is created, and tags are only inflated when asked
+/// for, so reading the song info does not decompress the MIDI or lyrics. The buffer is borrowed
+/// when created with [`EmkReader::from_decrypted`].
+pub struct EmkReader<'a> {
+    data: Cow<'a, [u8]>,
+    header_pos: usize,
+    header_end: usize,
+    entries: Vec<TagEntry>,
+}
+
+impl<'a> EmkReader<'a> {
+    pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Self> {
+        Self::new(xor(data, key)?)
+    }
+
+    /// Takes an already decrypted EMK file, and returns the reader
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        Self::from_cow(Cow::Owned(data))
+    }
+
+    /// Takes an already decrypted EMK file without copying it, and returns the reader
+    pub fn from_decrypted(data: &'a [u8]) -> Result<Self> {
+        Self::from_cow(Cow::Borrowed(data))
+    }
+
+    fn from_cow(data: Cow<'a, [u8]>) -> Result<Self> {
+        let read_u64 = |offset: usize| {
+            data.get(offset..offset + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or(EmkError::Truncated { tag: None, offset })
+        };
+        let header_pos = read_u64(0x22)?;
+        let header_end = read_u64(0x2a)?;
+
+        let header = data
+            .get(header_pos..header_end)
+            .ok_or(EmkError::InvalidRange {
+                tag: None,
+                begin: header_pos,
+                end: header_end,
+            })?;
+        let entries = read_entries(header, header_pos)?;
+
+        Ok(Self {
+            data,
+            header_pos,
+            header_end,
+            entries,
+        })
+    }
+
+    pub fn decrypt_default_key(data: &[u8]) -> Result<Self> {
+        Self::decrypt(data, EMK_MAGIC.to_be_bytes().as_ref())
+    }
+
+    /// Opens a seekable source without reading it into memory, see [`EmkStreamReader`]
+    pub fn from_reader<R: std::io::Read + std::io::Seek>(
+        reader: R,
+        key: &[u8],
+    ) -> Result<EmkStreamReader<R>> {
+        EmkStreamReader::new(reader, key)
+    }
+
+    /// Attempt to crack the key from known plaintext, falling back to Alula's algorithm
+    pub fn try_decrypt(data: &[u8]) -> Result<(Self, Vec<u8>)> {
+        Self::try_decrypt_with_options(data, &CrackOptions::default())
+    }
+
+    /// [`EmkReader::try_decrypt`], running Alula's algorithm as configured by `options`
+    pub fn try_decrypt_with_options(
+        data: &[u8],
+        options: &CrackOptions,
+    ) -> Result<(Self, Vec<u8>)> {
+        let key = xor_cracker_known_plaintext(data)
+            .or_else(|_| xor_cracker_alula_with_options(data, options))?;
+        Ok((Self::decrypt(data, &key)?, key))
+    }
+
+    /// Absolute offset of the tag table in the decrypted file
+    pub fn header_pos(&self) -> usize {
+        self.header_pos
+    }
+
+    /// Inflates every tag and checks it against the MD5 hash, size and offsets of its entry
+    pub fn verify(&self) -> IntegrityReport {
+        verify_reader(self)
+    }
+
+    /// The raw tag table
+    fn header(&self) -> &[u8] {
+        &self.data[self.header_pos..self.header_end]
+    }
+
+    /// Entries of the tag table, in the order they appear in the file
+    pub fn entries(&self) -> &[TagEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, tag: &str) -> Option<&TagEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    pub fn read_header(&self) -> Result<()> {
+        for entry in &self.entries {
+            debug!("=== Header ===\n{:#?}", entry);
+
+            let raw_data = self.inflate(entry)?;
+            debug!("Hash: {}", hex::encode(Md5::digest(&raw_data)));
+            debug!("Embedded Hash: {}", hex::encode(entry.md5_hash));
+
+            if let "HEADER" = entry.tag.as_str() {
+                debug!("--- HEADER ---");
+                debug!("{}", String::from_utf8_lossy(&raw_data));
+                debug!("--- END HEADER ---");
             }
         }
-
-        // reset pos to 0
-        self.pos = 0;
         Ok(())
     }
-    pub fn read_tags(&mut self) -> Vec<std::collections::BTreeMap<String, DataTypeOut>> {
+
+    pub fn read_tags(&self) -> Result<Vec<std::collections::BTreeMap<String, DataTypeOut>>> {
         let mut tags = Vec::new();
-        while self.pos < self.header.len() {
-            if !self.check_magic(MAGIC.as_ref()) {
+        let mut reader = TableReader::new(self.header(), self.header_pos);
+        while !reader.is_at_end() {
+            if !reader.check_magic(MAGIC.as_ref()) {
                 break;
             }
             let mut tag_map = std::collections::BTreeMap::new();
-            let tag = self.read_tag();
-            let uncompressed_size = self.read_tag();
-            let unk2 = self.read_tag();
-            let data_begin = self.read_tag();
-            let data_end = self.read_tag();
-            let unk5 = self.read_tag();
-            let unk6 = self.read_tag();
-            let md5_hash = {
-                let res = self.data[self.pos..self.pos + 16].to_vec();
-                self.pos += 16;
-                res
-            };
-            let unk7 = self.read_tag();
-            let unk8 = self.read_tag();
-
-            tag_map.insert("tag".to_string(), tag.clone());
+            let tag = reader.read_tag()?;
+            let uncompressed_size = reader.read_tag()?;
+            let unk2 = reader.read_tag()?;
+            let data_begin = reader.read_tag()?;
+            let data_end = reader.read_tag()?;
+            let unk5 = reader.read_tag()?;
+            let unk6 = reader.read_tag()?;
+            let md5_hash = reader.read_md5()?;
+            let unk7 = reader.read_tag()?;
+            let unk8 = reader.read_tag()?;
+
+            tag_map.insert("tag".to_string(), tag);
             tag_map.insert("uncompressed_size".to_string(), uncompressed_size);
             tag_map.insert("unk2".to_string(), unk2);
-            tag_map.insert("data_begin".to_string(), data_begin.clone());
-            tag_map.insert("data_end".to_string(), data_end.clone());
+            tag_map.insert("data_begin".to_string(), data_begin);
+            tag_map.insert("data_end".to_string(), data_end);
             tag_map.insert("unk5".to_string(), unk5);
             tag_map.insert("unk6".to_string(), unk6);
             tag_map.insert(
@@ -587,129 +1356,222 @@ impl EmkReader {
             tag_map.insert("unk7".to_string(), unk7);
             tag_map.insert("unk8".to_string(), unk8);
 
-            // Read and decompress the inner data
-            // let data_begin: u32 = data_begin.into();
-            // let data_end: u32 = data_end.into();
-            // let compressed_data = self.data[data_begin as usize..data_end as usize].to_vec();
-            // let raw_data = {
-            //     let mut buf = Vec::new();
-            //     let mut decoder = ZlibDecoder::new(compressed_data.as_slice());
-            //     decoder.read_to_end(&mut buf).unwrap();
-            //     buf
-            // };
-
-            // tag_map.insert("raw_data".to_string(), TagOut::Data(raw_data.clone()));
-
             tags.push(tag_map);
         }
-
-        // reset pos to 0
-        self.pos = 0;
-        tags
+        Ok(tags)
     }
 
-    pub fn into_emk_file(mut self) -> Result<EmkFile, String> {
+    pub fn into_emk_file(self) -> Result<EmkFile> {
         let mut data = Vec::new();
-        while self.pos < self.header.len() {
-            if !self.check_magic(MAGIC.as_ref()) {
-                return Err("Magic check failed".to_string());
-            }
-            let tag = self.read_tag();
-            let tag_string = if let DataTypeOut::String(s) = tag {
-                s
-            } else {
-                return Err("Invalid tag type".to_string());
-            };
-            let uncompressed_size = self.read_tag();
-            let unk2 = self.read_tag();
-            let data_begin = self.read_tag();
-            let data_end = self.read_tag();
-            let unk5 = self.read_tag();
-            let unk6 = self.read_tag();
-            let md5_hash = {
-                let res = self.data[self.pos..self.pos + 16].to_vec();
-                self.pos += 16;
-                res
-            };
-            let unk7 = self.read_tag();
-            let unk8 = self.read_tag();
-
-            let data_begin: u32 = data_begin.clone().into();
-            let data_end: u32 = data_end.clone().into();
-            let compressed_data = self.data[data_begin as usize..data_end as usize].to_vec();
-            let raw_data = {
-                let mut buf = Vec::new();
-                let mut decoder = ZlibDecoder::new(compressed_data.as_slice());
-                decoder.read_to_end(&mut buf).map_err(|e| e.to_string())?;
-                buf
-            };
+        for entry in &self.entries {
+            let raw_data = self.inflate(entry)?;
 
             data.push(Data {
-                tag: tag_string.clone(),
-                data_begin: data_begin.into(),
-                data_end: data_end.into(),
-                md5_hash: md5_hash
-                    .try_into()
-                    .map_err(|_| "Invalid MD5 hash length".to_string())?,
-                uncompressed_size: uncompressed_size.into(),
-                unk2: if let DataTypeOut::Byte(b) = unk2 {
-                    b != 0
-                } else {
-                    return Err("Invalid unk2 type".to_string());
-                },
-                unk5: if let DataTypeOut::Byte(b) = unk5 {
-                    b != 0
-                } else {
-                    return Err("Invalid unk5 type".to_string());
-                },
-                unk6: if let DataTypeOut::Byte(b) = unk6 {
-                    b != 0
-                } else {
-                    return Err("Invalid unk6 type".to_string());
-                },
-                unk7: if let DataTypeOut::String(s) = unk7 {
-                    s
-                } else {
-                    return Err("Invalid unk7 type".to_string());
-                },
-                unk8: if let DataTypeOut::Byte(b) = unk8 {
-                    b != 0
-                } else {
-                    return Err("Invalid unk8 type".to_string());
-                },
-                data: TagData::from_buf_with_tag(&tag_string, raw_data),
+                entry: entry.clone(),
+                data: TagData::from_buf_with_tag(&entry.tag, raw_data)?,
             });
         }
 
-        Ok(EmkFile(data))
+        let data_start = data
+            .iter()
+            .map(|d: &Data| d.entry.data_begin as usize)
+            .min()
+            .unwrap_or(self.header_pos)
+            .min(self.header_pos);
+
+        Ok(EmkFile {
+            tags: data,
+            preamble: self.data[..data_start].to_vec(),
+            trailer: self.data[self.header_end..].to_vec(),
+        })
     }
 
-    pub fn read_tag_data(&mut self, tag: &str) -> Option<Vec<u8>> {
-        let binding = self.read_tags();
-        let t = tag.to_string();
-        let tag = binding
-            .iter()
-            .find(|tag| matches!(tag.get("tag"), Some(DataTypeOut::String(s)) if *s == *t));
+    /// The compressed data of an entry, borrowed from the file
+    pub fn compressed_data(&self, entry: &TagEntry) -> Result<&[u8]> {
+        let (begin, end) = (entry.data_begin as usize, entry.data_end as usize);
+        self.data
+            .get(begin..end)
+            .ok_or_else(|| EmkError::InvalidRange {
+                tag: Some(entry.tag.clone()),
+                begin,
+                end,
+            })
+    }
+
+    /// Inflates the compressed data of an entry
+    pub fn inflate(&self, entry: &TagEntry) -> Result<Vec<u8>> {
+        let compressed_data = self.compressed_data(entry)?;
+        let mut buf = Vec::new();
+        let mut decoder = ZlibDecoder::new(compressed_data);
+        decoder
+            .read_to_end(&mut buf)
+            .map_err(|source| EmkError::Decompress {
+                tag: entry.tag.clone(),
+                offset: entry.data_begin as usize,
+                source,
+            })?;
+        Ok(buf)
+    }
+
+    /// Inflates the data of a single tag
+    pub fn read_tag_data(&self, tag: &str) -> Result<Vec<u8>> {
+        let entry = self
+            .entry(tag)
+            .ok_or_else(|| EmkError::MissingTag(tag.to_string()))?;
+        self.inflate(entry)
+    }
 
-        if let Some(tag) = tag {
-            // get data_begin and data_end
+    /// Inflates and parses a single tag
+    pub fn read_tag(&self, tag: &str) -> Result<TagData> {
+        TagData::from_buf_with_tag(tag, self.read_tag_data(tag)?)
+    }
 
-            let data_begin = tag.get("data_begin")?;
-            let data_end = tag.get("data_end")?;
+    /// Reads the song info without inflating any other tag
+    pub fn song_info(&self) -> Result<SongInfo> {
+        let data = self.read_tag_data("SONG_INFO")?;
+        SongInfo::from_kv_bytes(&data)
+    }
+}
 
-            let data_begin: u32 = data_begin.clone().into();
-            let data_end: u32 = data_end.clone().into();
-            let compressed_data = self.data[data_begin as usize..data_end as usize].to_vec();
-            let raw_data = {
-                let mut buf = Vec::new();
-                let mut decoder = ZlibDecoder::new(compressed_data.as_slice());
-                decoder.read_to_end(&mut buf).unwrap();
-                buf
-            };
+#[cfg(test)]
+mod tests {
+    use super::{EmkFile, TagData};
+    use crate::test_util::{sample, TEST_DATA};
+    use crate::util::xor;
+    use crate::util::EMK_MAGIC;
 
-            return Some(raw_data);
+    fn decrypted() -> Vec<u8> {
+        xor(TEST_DATA, &EMK_MAGIC.to_be_bytes()).unwrap()
+    }
+
+    /// Saves the file and reads it back
+    fn reload(file: &EmkFile) -> EmkFile {
+        EmkFile::from_bytes(&file.to_bytes().unwrap()).unwrap()
+    }
+
+    fn tag_bytes(file: &EmkFile, tag: &str) -> Vec<u8> {
+        file.get_data(tag).unwrap().data.to_bytes()
+    }
+
+    #[test]
+    fn test_transpose_reload() {
+        let original = sample();
+        let mut file = sample();
+        file.transpose(2).unwrap();
+        let file = reload(&file);
+        assert_eq!(file.song_info().unwrap().key, "G#m");
+        assert_eq!(file.lyrics().unwrap().key, "G#m");
+        let lyrics = tag_bytes(&original, "LYRIC_DATA");
+        let key = lyrics.windows(5).position(|w| w == b"\r\nF#m").unwrap();
+        let mut expected = lyrics.clone();
+        expected[key + 2..key + 5].copy_from_slice(b"G#m");
+        assert_eq!(tag_bytes(&file, "LYRIC_DATA"), expected);
+        assert_eq!(file.cursor().unwrap(), original.cursor().unwrap());
+        assert!(file.verify().is_ok());
+    }
+
+    #[test]
+    fn test_transpose_keeps_lyric_bytes() {
+        let mut file = sample();
+        // 0xDB is unassigned in Windows-874, and the lyrics mix line breaks and end with one
+        let lyrics = b"Title\r\nArtist\nF#m \r\n\r\n\xE0\xDB\nJenny\r\n".to_vec();
+        if let TagData::Lyrics(data) = &mut file.get_data_mut("LYRIC_DATA").unwrap().data {
+            **data = lyrics.clone();
         }
+        let original = file.midi().unwrap();
+        file.transpose(2).unwrap();
+        assert_eq!(
+            file.get_data("LYRIC_DATA").unwrap().data.to_bytes(),
+            b"Title\r\nArtist\nG#m \r\n\r\n\xE0\xDB\nJenny\r\n"
+        );
+        file.transpose(-2).unwrap();
+        assert_eq!(file.get_data("LYRIC_DATA").unwrap().data.to_bytes(), lyrics);
+        assert_eq!(file.midi().unwrap(), original);
+    }
+
+    #[test]
+    fn test_scale_tempo_reload() {
+        let original = sample();
+        let mut file = sample();
+        file.scale_tempo(0.5).unwrap();
+        let file = reload(&file);
+        let song_info = file.song_info().unwrap();
+        assert_eq!(song_info.tempo, 70);
+        assert_eq!(song_info.key, "F#m");
+        assert_eq!((song_info.start_time, song_info.stop_time), (0, 0));
+        // The cursor counts quarter notes, so neither it nor the lyrics change
+        assert_eq!(file.cursor().unwrap(), original.cursor().unwrap());
+        assert_eq!(
+            tag_bytes(&file, "LYRIC_DATA"),
+            tag_bytes(&original, "LYRIC_DATA")
+        );
+        assert_eq!(
+            file.time_base().unwrap().tempos()[0].micros_per_quarter,
+            857_142
+        );
+    }
+
+    #[test]
+    fn test_mute_vocals_reload() {
+        let original = sample();
+        let mut file = sample();
+        file.mute_vocals().unwrap();
+        let file = reload(&file);
+        assert_ne!(file.midi().unwrap(), original.midi().unwrap());
+        // Only MIDI_DATA changes
+        for tag in ["HEADER", "SONG_INFO", "LYRIC_DATA", "CURSOR_DATA"] {
+            assert_eq!(tag_bytes(&file, tag), tag_bytes(&original, tag), "{tag}");
+        }
+        let mut muted = original.midi().unwrap();
+        muted.set_channel_volume(8, 0.0).unwrap();
+        assert_eq!(file.midi().unwrap(), muted);
+    }
+
+    #[test]
+    fn test_truncated_files_do_not_panic() {
+        let data = decrypted();
+        // Only the trailer may be cut off
+        let header_end = u64::from_le_bytes(data[0x2a..0x32].try_into().unwrap()) as usize;
+        for len in 0..header_end {
+            assert!(EmkFile::from_bytes_decrypted(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_tag_table_does_not_panic() {
+        let data = decrypted();
+        let header_pos = u64::from_le_bytes(data[0x22..0x2a].try_into().unwrap()) as usize;
+        for pos in (0..0x32).chain(header_pos..data.len()) {
+            for value in [0x00, 0x01, 0x05, 0x7F, 0xFF] {
+                let mut data = data.clone();
+                data[pos] = value;
+                let _ = EmkFile::from_bytes_decrypted(&data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_song_info_does_not_inflate_midi() {
+        let mut data = decrypted();
+        let reader = super::EmkReader::from_decrypted(&data).unwrap();
+        let midi = reader.entry("MIDI_DATA").unwrap().data_begin as usize;
+        // Corrupt the MIDI data, which must not matter for reading the song info
+        data[midi + 2] ^= 0xFF;
+
+        let reader = super::EmkReader::from_decrypted(&data).unwrap();
+        assert_eq!(reader.song_info().unwrap().code, "000001");
+        assert_eq!(
+            reader.read_tag_data("MIDI_DATA").unwrap_err().tag(),
+            Some("MIDI_DATA")
+        );
+    }
 
-        None
+    #[test]
+    fn test_missing_song_info_field() {
+        let err = super::SongInfo::from_kv("CODE=000001\r\nTYPE=MIDI\r\n").unwrap_err();
+        assert!(matches!(
+            err,
+            crate::EmkError::MissingField { ref field, .. } if field == "SUB_TYPE"
+        ));
     }
 }
@@ -2,27 +2,37 @@ use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelRefMutIterator;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 use tracing::{info, trace};
 use xor_utils::avg_normalized_hamming_distance;
-const MAGIC_BYTES: &[u8; 5] = b".SFDS";
+
+use crate::error::{EmkError, Result};
+use crate::types::read_entries;
+pub(crate) const MAGIC_BYTES: &[u8; 5] = b".SFDS";
 
 pub const EMK_MAGIC: u64 = 0xAFF24C9CE9EA9943;
 
 #[tracing::instrument(skip(data))]
-pub fn xor(data: &[u8], key: &[u8]) -> Result<Vec<u8>, &'static str> {
+pub fn xor(data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     trace!("XORing data with key: {:X?}", key);
 
     if data.len() < MAGIC_BYTES.len() {
-        return Err("Data too short");
+        return Err(EmkError::Truncated {
+            tag: None,
+            offset: data.len(),
+        });
+    }
+
+    if key.is_empty() {
+        return Err(EmkError::InvalidKey);
     }
 
     // Verify magic bytes first
     for i in 0..MAGIC_BYTES.len() {
         if (data[i] ^ key[i % key.len()]) != MAGIC_BYTES[i] {
-            return Err("Invalid magic");
+            return Err(EmkError::InvalidKey);
         }
     }
 
@@ -34,8 +44,29 @@ pub fn xor(data: &[u8], key: &[u8]) -> Result<Vec<u8>, &'static str> {
         .collect())
 }
 
+/// XORs the data in place with the key, without checking for the magic bytes
+///
+/// Used for encrypting, where the input is plaintext
+pub fn xor_in_place(data: &mut [u8], key: &[u8]) {
+    xor_in_place_at(data, key, 0);
+}
+
+/// XORs the data in place with the key, as if `data` started at `offset` in the file
+///
+/// The cipher is position-based, so this can decrypt any range of a file on its own. An empty key
+/// leaves the data as it is.
+pub fn xor_in_place_at(data: &mut [u8], key: &[u8], offset: u64) {
+    if key.is_empty() {
+        return;
+    }
+    let len = key.len() as u64;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[((offset + i as u64) % len) as usize];
+    }
+}
+
 pub fn xor_verify(data: &[u8], key: &[u8]) -> bool {
-    if data.len() < MAGIC_BYTES.len() {
+    if key.is_empty() || data.len() < MAGIC_BYTES.len() {
         return false;
     }
 
@@ -70,71 +101,200 @@ pub fn xor_verify(data: &[u8], key: &[u8]) -> bool {
 //     result.ok_or("No valid key found")
 // }
 
-/// Attempts to brute-force the XOR key for the given data, iterating every single u64 value
+/// Progress of a running cracker
+#[derive(Debug, Clone, Copy)]
+pub struct CrackProgress {
+    /// Number of keys tried so far
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
+type ProgressCallback = Arc<dyn Fn(CrackProgress) + Send + Sync>;
+
+/// Controls how a cracker runs.
 ///
-/// WARNING: Very slow... Try to avoid using this function
-pub fn xor_cracker_bruteforce(data: &[u8]) -> Result<Vec<u8>, &'static str> {
-    use rayon::prelude::*;
+/// By default the crackers run on the global rayon pool, until they are done.
+#[derive(Clone, Default)]
+pub struct CrackOptions {
+    threads: Option<usize>,
+    pool: Option<Arc<rayon::ThreadPool>>,
+    progress: Option<ProgressCallback>,
+    progress_interval: Option<u64>,
+    cancel: Option<Arc<AtomicBool>>,
+    timeout: Option<Duration>,
+}
 
-    let max_threads = rayon::max_num_threads();
+impl CrackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    info!("Max threads: {}", max_threads);
+    /// Runs the cracker on a new pool with this many threads, which is torn down afterwards
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
 
-    let d = avg_normalized_hamming_distance(&data.to_vec(), 16);
-    info!("{d:#?}");
+    /// Runs the cracker on an existing pool
+    pub fn pool(mut self, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
 
-    // sort by value (ascending)
-    let mut d = d.into_iter().collect::<Vec<_>>();
-    d.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    /// Calls `progress` from the worker threads every `interval` attempts
+    pub fn progress(
+        mut self,
+        interval: u64,
+        progress: impl Fn(CrackProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self.progress_interval = Some(interval.max(1));
+        self
+    }
 
-    // get closest distance
-    let (keysize, _) = d.first().unwrap();
+    /// Stops the cracker with [`EmkError::Cancelled`] once the flag is set
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
 
-    info!("Keysize: {}", keysize);
+    /// Stops the cracker with [`EmkError::TimedOut`] after the timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-    info!("Cracking... by {} bytes", keysize);
-    // generate all possible keys by keysize
+    /// Runs `f` on the configured thread pool
+    fn install<T: Send>(&self, f: impl FnOnce() -> T + Send) -> Result<T> {
+        if let Some(pool) = &self.pool {
+            return Ok(pool.install(f));
+        }
+        match self.threads {
+            Some(threads) => {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| EmkError::Io(std::io::Error::other(e)))?;
+                Ok(pool.install(f))
+            }
+            None => Ok(f()),
+        }
+    }
+}
 
-    // generate a random key of keysize, then try with xor_verify
+/// Shared state of a running cracker, counting attempts and deciding when to stop
+struct CrackRun<'a> {
+    options: &'a CrackOptions,
+    start: Instant,
+    attempts: AtomicU64,
+    stopped: OnceLock<EmkError>,
+}
 
-    let total_attempts = Arc::new(AtomicU64::new(0));
-    let start_time = Instant::now();
+/// How often the clock is checked for the timeout
+const STOP_CHECK_INTERVAL: u64 = 1024;
 
-    let keys = (0..=u64::MAX).into_par_iter().filter_map(|key| {
-        let key_bytes = key.to_be_bytes();
-        let key = key_bytes
-            .iter()
-            .take(*keysize)
-            .cloned()
-            .collect::<Vec<u8>>();
-        total_attempts.fetch_add(1, Ordering::Relaxed);
-        Some(key)
-    });
-
-    let log_attempts = Arc::clone(&total_attempts);
-    std::thread::spawn(move || loop {
-        std::thread::sleep(Duration::from_secs(5));
-        let attempts = log_attempts.load(Ordering::Relaxed);
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let hashrate = (attempts as f64) / elapsed / 1_000_000.0;
-        info!("Attempts: {}, Hashrate: {:.2} MH/s", attempts, hashrate);
-    });
-
-    let result = keys.find_any(|key| {
-        // trace!("Trying key: {:X?}", key);
-        if xor_verify(data, key) {
-            info!("Found key: {:X?}", key);
-            true
-        } else {
-            false
+impl<'a> CrackRun<'a> {
+    fn new(options: &'a CrackOptions) -> Self {
+        Self {
+            options,
+            start: Instant::now(),
+            attempts: AtomicU64::new(0),
+            stopped: OnceLock::new(),
         }
-    });
+    }
 
-    if let Some(key) = result {
-        Ok(key)
-    } else {
-        Err("No valid key found")
+    /// Counts an attempt, returning true if the cracker should stop
+    fn attempt(&self) -> bool {
+        let attempts = self.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if let (Some(progress), Some(interval)) =
+            (&self.options.progress, self.options.progress_interval)
+        {
+            if attempts.is_multiple_of(interval) {
+                progress(CrackProgress {
+                    attempts,
+                    elapsed: self.start.elapsed(),
+                });
+            }
+        }
+
+        if self.stopped.get().is_some() {
+            return true;
+        }
+        if let Some(cancel) = &self.options.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                let _ = self.stopped.set(EmkError::Cancelled);
+                return true;
+            }
+        }
+        if let Some(timeout) = self.options.timeout {
+            if attempts.is_multiple_of(STOP_CHECK_INTERVAL) && self.start.elapsed() >= timeout {
+                let _ = self.stopped.set(EmkError::TimedOut);
+                return true;
+            }
+        }
+        false
     }
+
+    /// Turns the search result into the cracker's result
+    fn finish(self, key: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        let attempts = self.attempts.into_inner();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        info!(
+            "Attempts: {}, Hashrate: {:.2} MH/s",
+            attempts,
+            attempts as f64 / elapsed / 1_000_000.0
+        );
+        match (key, self.stopped.into_inner()) {
+            (Some(key), _) => Ok(key),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(EmkError::KeyNotFound),
+        }
+    }
+}
+
+/// Guesses the key length from the hamming distance between blocks of the data
+fn guess_key_length(data: &[u8]) -> Result<usize> {
+    let d = avg_normalized_hamming_distance(&data.to_vec(), 16);
+    d.into_iter()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(key_length, _)| key_length)
+        .ok_or(EmkError::KeyNotFound)
+}
+
+/// Attempts to brute-force the XOR key for the given data, iterating every single u64 value
+///
+/// WARNING: Very slow... Try to avoid using this function
+pub fn xor_cracker_bruteforce(data: &[u8]) -> Result<Vec<u8>> {
+    xor_cracker_bruteforce_with_options(data, &CrackOptions::default())
+}
+
+/// [`xor_cracker_bruteforce`], running as configured by `options`
+pub fn xor_cracker_bruteforce_with_options(data: &[u8], options: &CrackOptions) -> Result<Vec<u8>> {
+    let keysize = guess_key_length(data)?;
+    info!("Cracking... by {} bytes", keysize);
+
+    let run = CrackRun::new(options);
+    let result = options.install(|| {
+        // generate all possible keys by keysize, then try with xor_verify
+        (0..=u64::MAX).into_par_iter().find_map_any(|key| {
+            if run.attempt() {
+                // Stop the search without a key
+                return Some(None);
+            }
+            let key = key
+                .to_be_bytes()
+                .into_iter()
+                .take(keysize)
+                .collect::<Vec<u8>>();
+            xor_verify(data, &key).then(|| {
+                info!("Found key: {:X?}", key);
+                Some(key)
+            })
+        })
+    })?;
+
+    run.finish(result.flatten())
 }
 
 /// A modified XOR cracker that assumes the file contains mostly zeros,
@@ -146,69 +306,236 @@ pub fn xor_cracker_bruteforce(data: &[u8]) -> Result<Vec<u8>, &'static str> {
 /// Credits @alula on GitHub <3
 ///
 /// todo: Probably needs a more reliable way...
-pub fn xor_cracker_alula(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+pub fn xor_cracker_alula(data: &[u8]) -> Result<Vec<u8>> {
+    xor_cracker_alula_with_options(data, &CrackOptions::default())
+}
+
+/// [`xor_cracker_alula`], running as configured by `options`
+pub fn xor_cracker_alula_with_options(data: &[u8], options: &CrackOptions) -> Result<Vec<u8>> {
     // Get optimal key length using hamming distance
-    let d = avg_normalized_hamming_distance(&data.to_vec(), 16);
-    let (key_length, _) = d
-        .into_iter()
-        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
-        .unwrap();
-
-    // Pre-allocate key vector
-    let mut key = vec![0u8; key_length];
-
-    // Process bytes in parallel using rayon
-    key.par_iter_mut().enumerate().for_each(|(i, byte)| {
-        // Get frequency of bytes at this position
-        let freq = data.iter().skip(i).step_by(key_length).fold(
-            HashMap::with_capacity(256),
-            |mut map, &b| {
-                *map.entry(b).or_insert(0) += 1;
-                map
-            },
-        );
+    let key_length = guess_key_length(data)?;
+
+    let run = CrackRun::new(options);
+    let result = options.install(|| {
+        // Pre-allocate key vector
+        let mut key = vec![0u8; key_length];
+
+        // Process bytes in parallel using rayon
+        key.par_iter_mut().enumerate().for_each(|(i, byte)| {
+            // Get frequency of bytes at this position
+            let freq = data.iter().skip(i).step_by(key_length).fold(
+                HashMap::with_capacity(256),
+                |mut map, &b| {
+                    *map.entry(b).or_insert(0) += 1;
+                    map
+                },
+            );
+
+            // Most common byte is likely XORed with zero
+            *byte = freq
+                .into_iter()
+                .max_by_key(|&(_, count)| count)
+                .map(|(b, _)| b)
+                .unwrap_or(0);
+        });
+
+        // Try different position combinations in parallel
+        let positions: Vec<(usize, usize)> = (0..key.len())
+            .flat_map(|i| (i + 1..key.len()).map(move |j| (i, j)))
+            .collect();
+
+        positions.into_par_iter().find_map_any(|(pos1, pos2)| {
+            (0..=u16::MAX).into_par_iter().find_map_any(|n| {
+                if run.attempt() {
+                    return Some(None);
+                }
+                let mut test_key = key.clone();
+                test_key[pos1] = (n & 0xFF) as u8;
+                test_key[pos2] = (n >> 8) as u8;
+
+                if xor_verify(data, &test_key) {
+                    info!(
+                        "Found key with positions [{}, {}]: {:X?}",
+                        pos1, pos2, test_key
+                    );
+                    Some(Some(test_key))
+                } else {
+                    None
+                }
+            })
+        })
+    })?;
+
+    run.finish(result.flatten())
+}
+
+/// Longest key [`xor_cracker_known_plaintext`] tries
+pub const MAX_KEY_LENGTH: usize = 16;
 
-        // Most common byte is likely XORed with zero
-        *byte = freq
-            .into_iter()
-            .max_by_key(|&(_, count)| count)
-            .map(|(b, _)| b)
-            .unwrap_or(0);
-    });
-
-    // Try different position combinations in parallel
-    let positions: Vec<(usize, usize)> = (0..key.len())
-        .flat_map(|i| (i + 1..key.len()).map(move |j| (i, j)))
-        .collect();
-
-    let result = positions.into_par_iter().find_map_any(|(pos1, pos2)| {
-        (0..=u16::MAX).into_par_iter().find_map_any(|n| {
-            let mut test_key = key.clone();
-            test_key[pos1] = (n & 0xFF) as u8;
-            test_key[pos2] = (n >> 8) as u8;
-
-            if xor_verify(data, &test_key) {
-                info!(
-                    "Found key with positions [{}, {}]: {:X?}",
-                    pos1, pos2, test_key
-                );
-                Some(test_key)
-            } else {
-                None
+/// Offset of the header pointers, followed by the MD5 hash of the tag table
+const HEADER_POINTERS: usize = 0x22;
+/// Start of the tag table, assuming the `HEADER` tag comes first like in every known file
+const TABLE_PREFIX: &[u8] = b"SFDS\x06\x06HEADER";
+/// Number of key bytes that may be guessed when the known plaintext does not cover them
+const MAX_GUESSES: usize = 2;
+
+/// Recovers the key from the parts of the file whose plaintext is known.
+///
+/// The file always starts with `.SFDS`, and the header pointers at `0x22` are u64 offsets whose
+/// upper bytes are zero. Once the pointer to the tag table is decrypted, the table is known to
+/// start with an `SFDS` record for the `HEADER` tag. Files written by Extreme Karaoke also end with
+/// 8 zero bytes right after the table, which is tried first, and files missing them end with the
+/// table. Key bytes not covered by any of these are guessed. Guesses are checked by decrypting only
+/// the header pointers and the tag table, which accepts the same keys as [`xor_verify`] without
+/// decrypting the whole file for every guess.
+///
+/// Every key length up to [`MAX_KEY_LENGTH`] is tried, shortest first.
+pub fn xor_cracker_known_plaintext(data: &[u8]) -> Result<Vec<u8>> {
+    (1..=MAX_KEY_LENGTH)
+        .find_map(|len| {
+            let mut key = PartialKey::new(len);
+            let known = key.learn(data, 0, MAGIC_BYTES)
+                && key.learn(data, HEADER_POINTERS + 4, &[0; 4])
+                && key.learn(data, HEADER_POINTERS + 12, &[0; 4]);
+            if !known {
+                return None;
             }
+
+            let mut with_trailer = key.clone();
+            let header_end = (data.len() as u64).saturating_sub(8);
+            let trailer_known = with_trailer.learn(data, data.len().saturating_sub(8), &[0; 8])
+                && with_trailer.learn(data, HEADER_POINTERS + 8, &header_end.to_le_bytes());
+
+            let mut table_at_end = key.clone();
+            let header_end = data.len() as u64;
+            let table_end_known =
+                table_at_end.learn(data, HEADER_POINTERS + 8, &header_end.to_le_bytes());
+
+            trailer_known
+                .then(|| solve_key(data, with_trailer, 0))
+                .flatten()
+                .or_else(|| {
+                    table_end_known
+                        .then(|| solve_key(data, table_at_end, 0))
+                        .flatten()
+                })
+                .or_else(|| solve_key(data, key, 0))
         })
-    });
+        .inspect(|key| info!("Found key: {:X?}", key))
+        .ok_or(EmkError::KeyNotFound)
+}
+
+/// A key of a fixed length with some bytes still unknown
+#[derive(Clone)]
+struct PartialKey {
+    key: Vec<Option<u8>>,
+}
+
+impl PartialKey {
+    fn new(len: usize) -> Self {
+        Self {
+            key: vec![None; len],
+        }
+    }
+
+    /// Learns the key bytes covering `plain` at `pos`, returning false if they contradict what is
+    /// already known or lie outside the data
+    fn learn(&mut self, data: &[u8], pos: usize, plain: &[u8]) -> bool {
+        let Some(cipher) = data.get(pos..pos.saturating_add(plain.len())) else {
+            return false;
+        };
+        let len = self.key.len();
+        for (i, (&c, &p)) in cipher.iter().zip(plain).enumerate() {
+            let slot = &mut self.key[(pos + i) % len];
+            match slot {
+                Some(k) if *k != c ^ p => return false,
+                _ => *slot = Some(c ^ p),
+            }
+        }
+        true
+    }
+
+    /// Decrypts a range, if every key byte needed for it is known
+    fn decrypt(&self, data: &[u8], pos: usize, len: usize) -> Option<Vec<u8>> {
+        data.get(pos..pos.checked_add(len)?)?
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| Some(c ^ self.key[(pos + i) % self.key.len()]?))
+            .collect()
+    }
+
+    fn decrypt_u64(&self, data: &[u8], pos: usize) -> Option<usize> {
+        let bytes = self.decrypt(data, pos, 8)?;
+        usize::try_from(u64::from_le_bytes(bytes.try_into().ok()?)).ok()
+    }
+
+    /// The next key byte to guess, preferring the ones needed to find the tag table
+    fn next_unknown(&self) -> Option<usize> {
+        let len = self.key.len();
+        (HEADER_POINTERS..HEADER_POINTERS + 8)
+            .map(|pos| pos % len)
+            .chain(0..len)
+            .find(|&i| self.key[i].is_none())
+    }
+}
+
+fn solve_key(data: &[u8], mut key: PartialKey, guesses: usize) -> Option<Vec<u8>> {
+    if let Some(header_pos) = key.decrypt_u64(data, HEADER_POINTERS) {
+        if !key.learn(data, header_pos, TABLE_PREFIX) {
+            return None;
+        }
+    }
+
+    match key.next_unknown() {
+        Some(_) if guesses == MAX_GUESSES => None,
+        Some(i) => (0..=u8::MAX).find_map(|b| {
+            let mut key = key.clone();
+            key.key[i] = Some(b);
+            solve_key(data, key, guesses + 1)
+        }),
+        None => {
+            let key = key.key.into_iter().collect::<Option<Vec<u8>>>()?;
+            table_verify(data, &key).then_some(key)
+        }
+    }
+}
 
-    result.ok_or("No valid key found")
+/// Checks a complete key by decrypting only the magic, the header pointers and the tag table
+fn table_verify(data: &[u8], key: &[u8]) -> bool {
+    if key.is_empty() {
+        return false;
+    }
+    let decrypt = |pos: usize, len: usize| {
+        let mut bytes = data.get(pos..pos.checked_add(len)?)?.to_vec();
+        xor_in_place_at(&mut bytes, key, pos as u64);
+        Some(bytes)
+    };
+    let pointer = |pos| {
+        let bytes = decrypt(pos, 8)?;
+        usize::try_from(u64::from_le_bytes(bytes.try_into().ok()?)).ok()
+    };
+    let table_valid = || {
+        let (begin, end) = (pointer(HEADER_POINTERS)?, pointer(HEADER_POINTERS + 8)?);
+        let table = decrypt(begin, end.checked_sub(begin)?)?;
+        Some(read_entries(&table, begin).is_ok())
+    };
+    decrypt(0, MAGIC_BYTES.len()).is_some_and(|magic| magic == MAGIC_BYTES)
+        && table_valid().unwrap_or(false)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
     use tracing_test::traced_test;
 
-    static VALID_KEY: [u8; 8] = [0xAF, 0xF2, 0x4C, 0x9C, 0xE9, 0xEA, 0x99, 0x43];
-    static TEST_DATA: &[u8] = include_bytes!("../examples/000001.emk");
+    use super::CrackOptions;
+    use crate::test_util::{sample, TEST_DATA};
+    use crate::EmkError;
 
+    static VALID_KEY: [u8; 8] = [0xAF, 0xF2, 0x4C, 0x9C, 0xE9, 0xEA, 0x99, 0x43];
     #[traced_test]
     #[test]
     fn test_xor() {
@@ -216,6 +543,100 @@ mod tests {
         assert_eq!(result.len(), TEST_DATA.len());
     }
 
+    #[traced_test]
+    #[test]
+    fn test_known_plaintext_xor_cracker() {
+        let key = super::xor_cracker_known_plaintext(TEST_DATA).unwrap();
+        assert_eq!(key, VALID_KEY);
+    }
+
+    #[test]
+    fn test_known_plaintext_xor_cracker_other_keys() {
+        let file = sample();
+        for key in [
+            &[0x5A][..],
+            &[0x01, 0x23, 0x45, 0x67, 0x89],
+            &[
+                0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
+            ],
+            &[
+                0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFE, 0xEF, 0xCD, 0xAB, 0x89, 0x67, 0x45,
+                0x23, 0x01,
+            ],
+        ] {
+            let data = file.to_bytes_with_key(key).unwrap();
+            assert_eq!(super::xor_cracker_known_plaintext(&data).unwrap(), key);
+
+            // Without the trailer, the remaining key bytes have to come from the tag table
+            let data = &data[..data.len() - 8];
+            let found = super::xor_cracker_known_plaintext(data).unwrap();
+            assert!(super::xor_verify(data, &found));
+        }
+    }
+
+    #[test]
+    fn test_table_verify() {
+        let data = &TEST_DATA[..TEST_DATA.len() - 8];
+        assert!(super::table_verify(TEST_DATA, &VALID_KEY));
+        assert!(super::table_verify(data, &VALID_KEY));
+        let mut key = VALID_KEY;
+        for i in 0..key.len() {
+            key[i] ^= 1;
+            assert_eq!(
+                super::table_verify(TEST_DATA, &key),
+                super::xor_verify(TEST_DATA, &key)
+            );
+            key[i] ^= 1;
+        }
+    }
+
+    #[test]
+    fn test_empty_key() {
+        assert!(super::xor(TEST_DATA, &[]).is_err());
+        assert!(!super::xor_verify(TEST_DATA, &[]));
+        let decrypted = super::xor(TEST_DATA, &VALID_KEY).unwrap();
+        assert!(!super::table_verify(&decrypted, &[]));
+        let mut data = TEST_DATA[..64].to_vec();
+        super::xor_in_place_at(&mut data, &[], 3);
+        assert_eq!(data, TEST_DATA[..64]);
+    }
+
+    #[test]
+    fn test_cracker_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let options = CrackOptions::new().threads(2).cancel(cancel);
+        let err = super::xor_cracker_bruteforce_with_options(TEST_DATA, &options).unwrap_err();
+        assert!(matches!(err, EmkError::Cancelled));
+    }
+
+    #[test]
+    fn test_cracker_timeout_and_progress() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let seen = Arc::clone(&attempts);
+        let options = CrackOptions::new()
+            .threads(2)
+            .timeout(Duration::from_millis(200))
+            .progress(1000, move |p| {
+                seen.fetch_max(p.attempts, Ordering::Relaxed);
+            });
+        let err = super::xor_cracker_bruteforce_with_options(TEST_DATA, &options).unwrap_err();
+        assert!(matches!(err, EmkError::TimedOut));
+        assert!(attempts.load(Ordering::Relaxed) >= 1000);
+    }
+
+    #[test]
+    fn test_alula_xor_cracker_on_pool() {
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap(),
+        );
+        let options = CrackOptions::new().pool(pool);
+        let key = super::xor_cracker_alula_with_options(TEST_DATA, &options).unwrap();
+        assert_eq!(key, VALID_KEY);
+    }
+
     #[traced_test]
     #[test]
     fn test_alula_xor_cracker() {
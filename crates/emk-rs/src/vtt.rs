@@ -0,0 +1,90 @@
+use std::fmt::Write;
+
+use crate::timed::TimedLine;
+use crate::types::SongInfo;
+
+/// Formats milliseconds as a WebVTT timestamp, `hh:mm:ss.mmm`
+pub fn format_timestamp(ms: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Writes WebVTT subtitles from timed lines in milliseconds.
+///
+/// Every sung line is a cue, and every syllable after the first is preceded by an inline
+/// `<hh:mm:ss.mmm>` timestamp, which browsers use for the `:past` and `:future` styles.
+pub fn to_webvtt(lines: &[TimedLine], song: Option<&SongInfo>) -> String {
+    let mut out = String::from("WEBVTT");
+    if let Some(song) = song {
+        let _ = write!(out, " - {} - {}", song.title.trim(), song.artist.trim());
+    }
+    out.push_str("\n\n");
+
+    let lines = lines.iter().filter(|line| !line.text().trim().is_empty());
+    for line in lines {
+        let (Some(start), Some(end)) = (line.start(), line.end()) else {
+            continue;
+        };
+        let _ = writeln!(
+            out,
+            "{} --> {}",
+            format_timestamp(start),
+            format_timestamp(end)
+        );
+        let mut text = String::new();
+        for (i, syllable) in line.syllables.iter().enumerate() {
+            if i > 0 {
+                let _ = write!(text, "<{}>", format_timestamp(syllable.start));
+            }
+            text.push_str(&escape(&syllable.text));
+        }
+        let _ = writeln!(out, "{}", text.trim_end());
+        let _ = writeln!(out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_webvtt;
+    use crate::test_util::sample;
+    use crate::timed::{TimedLine, TimedSyllable};
+
+    #[test]
+    fn test_cue() {
+        let syllable = |text: &str, start, end| TimedSyllable {
+            text: text.to_string(),
+            start,
+            end,
+        };
+        let lines = [TimedLine {
+            syllables: vec![syllable(">>>Jen", 1000, 1500), syllable("ny", 1500, 2000)],
+        }];
+        assert_eq!(
+            to_webvtt(&lines, None),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\n&gt;&gt;&gt;Jen<00:00:01.500>ny\n\n"
+        );
+    }
+
+    #[test]
+    fn test_webvtt() {
+        let file = sample();
+        let vtt = file.to_webvtt().unwrap();
+        assert!(vtt.starts_with("WEBVTT - 8675309Jenny Jenny - Tommy Tutone\n\n00:00:00.196 --> "));
+        assert_eq!(
+            vtt.matches(" --> ").count(),
+            file.to_srt().unwrap().matches(" --> ").count()
+        );
+    }
+}
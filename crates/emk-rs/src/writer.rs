@@ -0,0 +1,258 @@
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use md5::{Digest, Md5};
+
+use crate::error::{EmkError, Result};
+use crate::types::{DataType, DataTypeOut, EmkFile, TagEntry, MAGIC};
+use crate::util::{xor_in_place, EMK_MAGIC};
+
+/// Offset of the u64 pointer to the start of the tag table
+const HEADER_POS_OFFSET: usize = 0x22;
+/// Offset of the u64 pointer to the end of the tag table
+const HEADER_END_OFFSET: usize = 0x2a;
+/// Offset of the MD5 hash of the tag table
+const HEADER_HASH_OFFSET: usize = 0x33;
+
+/// Serializes an [`EmkFile`] back into an EMK archive.
+///
+/// The payloads are laid out one after another right after the preamble, followed by the tag table
+/// and the trailer, the same way Extreme Karaoke writes them.
+pub struct EmkWriter {
+    key: Vec<u8>,
+}
+
+impl EmkWriter {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    pub fn default_key() -> Self {
+        Self::new(EMK_MAGIC.to_be_bytes().as_ref())
+    }
+
+    /// Serializes the file and encrypts it with the writer's key
+    pub fn write(&self, file: &EmkFile) -> Result<Vec<u8>> {
+        if self.key.is_empty() {
+            return Err(EmkError::InvalidKey);
+        }
+        let mut data = Self::write_decrypted(file)?;
+        xor_in_place(&mut data, &self.key);
+        Ok(data)
+    }
+
+    /// Serializes the file without encrypting it
+    pub fn write_decrypted(file: &EmkFile) -> Result<Vec<u8>> {
+        if file.preamble.len() < HEADER_HASH_OFFSET + 16 {
+            return Err(EmkError::InvalidPreamble);
+        }
+
+        let mut out = file.preamble.clone();
+        let mut table = Vec::new();
+
+        for data in &file.tags {
+            let raw_data = data.data.to_bytes();
+            let compressed_data = compress(&raw_data)?;
+
+            let data_begin = out.len() as u64;
+            out.extend_from_slice(&compressed_data);
+            let data_end = out.len() as u64;
+
+            let entry = TagEntry {
+                uncompressed_size: raw_data.len() as u64,
+                data_begin,
+                data_end,
+                md5_hash: Md5::digest(&raw_data).into(),
+                ..data.entry.clone()
+            };
+            write_entry(&mut table, &entry)?;
+        }
+
+        let header_pos = out.len() as u64;
+        out.extend_from_slice(&table);
+        let header_end = out.len() as u64;
+        out.extend_from_slice(&file.trailer);
+
+        out[HEADER_POS_OFFSET..HEADER_POS_OFFSET + 8].copy_from_slice(&header_pos.to_le_bytes());
+        out[HEADER_END_OFFSET..HEADER_END_OFFSET + 8].copy_from_slice(&header_end.to_le_bytes());
+        out[HEADER_HASH_OFFSET..HEADER_HASH_OFFSET + 16].copy_from_slice(&Md5::digest(&table));
+
+        Ok(out)
+    }
+}
+
+/// Compresses a payload the same way Extreme Karaoke does (zlib, fastest level). The output only
+/// matches Extreme Karaoke byte for byte with the `zlib` feature, as miniz_oxide picks different
+/// matches.
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn write_entry(table: &mut Vec<u8>, entry: &TagEntry) -> Result<()> {
+    let tag = &entry.tag;
+    table.extend_from_slice(&MAGIC);
+    write_string(table, tag, "tag", tag)?;
+    write_int(table, tag, "uncompressed_size", entry.uncompressed_size)?;
+    write_value(table, tag, "unk2", &entry.unk2)?;
+    write_int(table, tag, "data_begin", entry.data_begin)?;
+    write_int(table, tag, "data_end", entry.data_end)?;
+    write_value(table, tag, "unk5", &entry.unk5)?;
+    write_value(table, tag, "unk6", &entry.unk6)?;
+    table.extend_from_slice(&entry.md5_hash);
+    write_value(table, tag, "unk7", &entry.unk7)?;
+    write_value(table, tag, "unk8", &entry.unk8)?;
+    Ok(())
+}
+
+fn too_large(tag: &str, field: &'static str) -> EmkError {
+    EmkError::ValueTooLarge {
+        tag: tag.to_string(),
+        field,
+    }
+}
+
+fn write_byte(table: &mut Vec<u8>, value: u8) {
+    table.push(DataType::Byte as u8);
+    table.push(value);
+}
+
+/// Writes an integer using the smallest type that fits, treating bytes and shorts as signed like
+/// the original writer does
+fn write_int(table: &mut Vec<u8>, tag: &str, field: &'static str, value: u64) -> Result<()> {
+    if value <= i8::MAX as u64 {
+        write_byte(table, value as u8);
+    } else if value <= i16::MAX as u64 {
+        table.push(DataType::Short as u8);
+        table.extend_from_slice(&(value as u16).to_le_bytes());
+    } else {
+        let value = u32::try_from(value).map_err(|_| too_large(tag, field))?;
+        table.push(DataType::Int as u8);
+        table.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Writes a field with the data type it was read with
+fn write_value(
+    table: &mut Vec<u8>,
+    tag: &str,
+    field: &'static str,
+    value: &DataTypeOut,
+) -> Result<()> {
+    match value {
+        DataTypeOut::Byte(b) => write_byte(table, *b),
+        DataTypeOut::Short(s) => {
+            table.push(DataType::Short as u8);
+            table.extend_from_slice(&s.to_le_bytes());
+        }
+        DataTypeOut::Int(i) => {
+            table.push(DataType::Int as u8);
+            table.extend_from_slice(&i.to_le_bytes());
+        }
+        DataTypeOut::String(s) => write_string(table, tag, field, s)?,
+        // Raw bytes have no data type prefix
+        DataTypeOut::Data(d) => table.extend_from_slice(d),
+    }
+    Ok(())
+}
+
+fn write_string(table: &mut Vec<u8>, tag: &str, field: &'static str, value: &str) -> Result<()> {
+    let len = u8::try_from(value.len()).map_err(|_| too_large(tag, field))?;
+    table.push(DataType::String as u8);
+    table.push(len);
+    table.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::sample;
+    use crate::types::{DataTypeOut, EmkFile, SongInfo, TagData};
+
+    #[test]
+    fn test_round_trip() {
+        let file = sample();
+        let data = file.to_bytes().unwrap();
+        let reread = EmkFile::from_bytes(&data).unwrap();
+        assert!(reread.verify().is_ok());
+        assert_eq!(reread.preamble[..0x22], file.preamble[..0x22]);
+        assert_eq!(reread.trailer, file.trailer);
+        assert_eq!(reread.tags.len(), file.tags.len());
+        for (a, b) in reread.tags.iter().zip(&file.tags) {
+            assert_eq!(a.tag(), b.tag());
+            assert_eq!(a.data.to_bytes(), b.data.to_bytes());
+        }
+        assert_eq!(reread.to_bytes().unwrap(), data);
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn test_round_trip_exact() {
+        let file = sample();
+        let data = file.to_bytes().unwrap();
+        assert_eq!(data, crate::test_util::TEST_DATA);
+    }
+
+    #[test]
+    fn test_unknown_fields_preserved() {
+        let mut file = sample();
+        let entry = &mut file.get_data_mut("LYRIC_DATA").unwrap().entry;
+        entry.unk6 = DataTypeOut::Short(0x1234);
+        entry.unk7 = DataTypeOut::String("abc".to_string());
+
+        let data = file.to_bytes().unwrap();
+        let file = EmkFile::from_bytes(&data).unwrap();
+        let entry = &file.get_data("LYRIC_DATA").unwrap().entry;
+        assert!(matches!(entry.unk6, DataTypeOut::Short(0x1234)));
+        assert!(matches!(&entry.unk7, DataTypeOut::String(s) if s == "abc"));
+        assert_eq!(file.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn test_edit_song_info() {
+        let mut file = sample();
+        if let TagData::SongInfo(s) = &mut file.get_data_mut("SONG_INFO").unwrap().data {
+            s.title = "Jenny Jenny".to_string();
+        }
+
+        let data = file.to_bytes().unwrap();
+        let file = EmkFile::from_bytes(&data).unwrap();
+        match &file.get_data("SONG_INFO").unwrap().data {
+            TagData::SongInfo(s) => assert_eq!(s.title, "Jenny Jenny"),
+            _ => panic!("SONG_INFO is not song info"),
+        }
+    }
+
+    #[test]
+    fn test_song_info_keeps_raw_text() {
+        let mut file = sample();
+        // A Thai title in Windows-874 ending in the unassigned byte 0xDB, and an unknown key
+        let mut raw = Vec::new();
+        for line in file.song_info().unwrap().to_kv().lines() {
+            match line.strip_prefix("TITLE=") {
+                Some(_) => raw.extend_from_slice(b"TITLE=\xE0\xBE\xC5\xA7\xDB"),
+                None => raw.extend_from_slice(line.as_bytes()),
+            }
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"EXTRA=1");
+        *file.song_info_mut().unwrap() = SongInfo::from_kv_bytes(&raw).unwrap();
+        assert_eq!(file.song_info().unwrap().title, "เพลง\u{FFFD}");
+
+        let song_info = |file: &EmkFile| {
+            let data = file.to_bytes().unwrap();
+            let file = EmkFile::from_bytes(&data).unwrap();
+            file.get_data("SONG_INFO").unwrap().data.to_bytes()
+        };
+        assert_eq!(song_info(&file), raw);
+
+        // Only the changed line is rewritten
+        file.song_info_mut().unwrap().key = "G#m".to_string();
+        let key = raw.windows(7).position(|w| w == b"KEY=F#m").unwrap();
+        raw[key..key + 7].copy_from_slice(b"KEY=G#m");
+        assert_eq!(song_info(&file), raw);
+    }
+}